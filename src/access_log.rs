@@ -0,0 +1,167 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{Method, StatusCode};
+use serde::Serialize;
+
+/// Placeholders supported in `log_template`
+const FIELDS: &[&str] = &[
+    "method",
+    "path",
+    "remote_addr",
+    "status",
+    "response_bytes",
+    "duration",
+    "duration_ms",
+    "rpc_method",
+    "backend",
+    "key_id",
+    "request_id",
+];
+
+enum Segment {
+    Literal(String),
+    Field(&'static str),
+}
+
+/// A request log line template such as
+/// `"{method} {backend} {status} {duration_ms} {rpc_method}"`, parsed once at
+/// startup. Fields without a value for a request render as `-`.
+pub struct LogTemplate {
+    segments: Vec<Segment>,
+}
+
+/// Values available for one request log line
+pub struct LogRecord<'a> {
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub remote_addr: SocketAddr,
+    pub status: StatusCode,
+    /// Response body size, when the response declares a `Content-Length`
+    pub response_bytes: Option<u64>,
+    pub duration: Duration,
+    pub rpc_method: Option<&'a str>,
+    pub backend: Option<&'a str>,
+    pub key_id: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+}
+
+impl LogTemplate {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|i| start + i)
+                .ok_or_else(|| format!("Unclosed placeholder in log template: {}", template))?;
+            let name = &rest[start + 1..end];
+            let field = FIELDS
+                .iter()
+                .find(|f| **f == name)
+                .ok_or_else(|| format!("Unknown log template placeholder '{{{}}}'", name))?;
+            segments.push(Segment::Field(field));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, record: &LogRecord) -> String {
+        let mut line = String::new();
+        for segment in &self.segments {
+            let _ = match segment {
+                Segment::Literal(text) => write!(line, "{}", text),
+                Segment::Field("method") => write!(line, "{}", record.method),
+                Segment::Field("path") => write!(line, "{}", record.path),
+                Segment::Field("remote_addr") => write!(line, "{}", record.remote_addr),
+                Segment::Field("status") => write!(line, "{}", record.status.as_u16()),
+                Segment::Field("response_bytes") => match record.response_bytes {
+                    Some(bytes) => write!(line, "{}", bytes),
+                    None => write!(line, "-"),
+                },
+                Segment::Field("duration") => write!(line, "{:?}", record.duration),
+                Segment::Field("duration_ms") => {
+                    write!(line, "{:.3}", record.duration.as_secs_f64() * 1000.0)
+                }
+                Segment::Field("rpc_method") => {
+                    write!(line, "{}", record.rpc_method.unwrap_or("-"))
+                }
+                Segment::Field("backend") => write!(line, "{}", record.backend.unwrap_or("-")),
+                Segment::Field("key_id") => write!(line, "{}", record.key_id.unwrap_or("-")),
+                Segment::Field("request_id") => {
+                    write!(line, "{}", record.request_id.unwrap_or("-"))
+                }
+                Segment::Field(_) => Ok(()),
+            };
+        }
+        line
+    }
+}
+
+/// One entry in the `/admin/recent` ring buffer
+#[derive(Clone, Serialize)]
+pub struct RecentRequest {
+    /// Completion time in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub request_id: Option<String>,
+    pub rpc_method: Option<String>,
+    pub backend: Option<String>,
+    pub status: u16,
+    pub duration_ms: f64,
+}
+
+/// Bounded buffer of the most recently completed requests
+pub struct RecentRequests {
+    capacity: usize,
+    entries: Mutex<VecDeque<RecentRequest>>,
+}
+
+impl RecentRequests {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, record: &LogRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let entry = RecentRequest {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            request_id: record.request_id.map(str::to_string),
+            rpc_method: record.rpc_method.map(str::to_string),
+            backend: record.backend.map(str::to_string),
+            status: record.status.as_u16(),
+            duration_ms: record.duration.as_secs_f64() * 1000.0,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Buffered entries, newest first
+    pub fn snapshot(&self) -> Vec<RecentRequest> {
+        self.entries.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
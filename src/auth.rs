@@ -0,0 +1,213 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tokio::sync::Semaphore;
+
+use crate::metrics::api_key_id;
+
+/// Argon2 checks run at once for client requests, so unknown keys can't
+/// occupy more than this many blocking threads
+const ARGON2_CONCURRENCY: usize = 2;
+
+/// Candidates remembered as matching no argon2 entry
+const MAX_REJECTED_KEYS: usize = 10_000;
+
+/// A configured API key: plaintext, or `sha256:<hex>` / `argon2:<phc>` so the
+/// raw key doesn't have to be stored in the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ApiKey {
+    Plain(String),
+    Sha256([u8; 32]),
+    Argon2(String),
+}
+
+impl TryFrom<String> for ApiKey {
+    type Error = String;
+
+    fn try_from(entry: String) -> Result<Self, Self::Error> {
+        if let Some(hex) = entry.strip_prefix("sha256:") {
+            let digest = decode_hex(hex)
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .ok_or("sha256: API key must be 64 hex characters")?;
+            return Ok(ApiKey::Sha256(digest));
+        }
+        if let Some(phc) = entry.strip_prefix("argon2:") {
+            PasswordHash::new(phc).map_err(|e| format!("invalid argon2: API key: {}", e))?;
+            return Ok(ApiKey::Argon2(phc.to_string()));
+        }
+        if entry.is_empty() {
+            return Err("API keys must not be empty".into());
+        }
+        Ok(ApiKey::Plain(entry))
+    }
+}
+
+impl ApiKey {
+    /// Identifier used in metrics and logs. For plaintext and sha256 entries
+    /// this is the `api_key_id` of the raw key; argon2 entries are identified
+    /// by a hash of their PHC string, since the raw key is never known.
+    pub fn id(&self) -> String {
+        match self {
+            ApiKey::Plain(key) => api_key_id(key),
+            ApiKey::Sha256(digest) => digest[..8].iter().map(|b| format!("{:02x}", b)).collect(),
+            ApiKey::Argon2(phc) => api_key_id(phc),
+        }
+    }
+
+    pub fn verify(&self, candidate: &str) -> bool {
+        match self {
+            ApiKey::Plain(key) => key.as_bytes().ct_eq(candidate.as_bytes()).into(),
+            ApiKey::Sha256(digest) => Sha256::digest(candidate.as_bytes())
+                .as_slice()
+                .ct_eq(digest)
+                .into(),
+            ApiKey::Argon2(phc) => PasswordHash::new(phc).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(candidate.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Bounded set of key digests, evicting the oldest first
+#[derive(Default)]
+struct RejectedKeys {
+    digests: HashSet<[u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl RejectedKeys {
+    fn insert(&mut self, digest: [u8; 32]) {
+        if !self.digests.insert(digest) {
+            return;
+        }
+        self.order.push_back(digest);
+        if self.order.len() > MAX_REJECTED_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.digests.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The configured client API keys. Argon2 verification is deliberately slow,
+/// so the outcome for a candidate is remembered (by SHA-256 digest): keys
+/// that matched an argon2 entry skip the check later, and recent keys that
+/// matched none are rejected without it.
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+    verified: RwLock<HashMap<[u8; 32], String>>,
+    rejected: Mutex<RejectedKeys>,
+    argon2_permits: Semaphore,
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKey>) -> Self {
+        Self {
+            keys,
+            verified: RwLock::new(HashMap::new()),
+            rejected: Mutex::new(RejectedKeys::default()),
+            argon2_permits: Semaphore::new(ARGON2_CONCURRENCY),
+        }
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.keys.iter().map(ApiKey::id).collect()
+    }
+
+    /// The id of the configured key given either as the key itself or as
+    /// its id
+    pub fn id_for(&self, key_or_id: &str) -> Option<String> {
+        self.keys
+            .iter()
+            .map(ApiKey::id)
+            .find(|id| id == key_or_id)
+            .or_else(|| self.verify(key_or_id))
+    }
+
+    /// The id of the configured key matching `candidate`, if any. Plaintext
+    /// and sha256 entries are all compared in constant time, so the position
+    /// of a matching entry isn't revealed through timing.
+    pub fn verify(&self, candidate: &str) -> Option<String> {
+        let digest: [u8; 32] = Sha256::digest(candidate.as_bytes()).into();
+        self.verify_cached(candidate, &digest)
+            .or_else(|| self.verify_argon2(candidate, digest))
+    }
+
+    /// `verify` for client requests: argon2 checks run on the blocking pool,
+    /// at most `ARGON2_CONCURRENCY` at a time, and keys recently found to
+    /// match nothing are rejected without one
+    pub async fn verify_request(self: &Arc<Self>, candidate: &str) -> Option<String> {
+        let digest: [u8; 32] = Sha256::digest(candidate.as_bytes()).into();
+        if let Some(id) = self.verify_cached(candidate, &digest) {
+            return Some(id);
+        }
+        if !self.keys.iter().any(|k| matches!(k, ApiKey::Argon2(_)))
+            || self.rejected.lock().unwrap().digests.contains(&digest)
+        {
+            return None;
+        }
+
+        let _permit = self.argon2_permits.acquire().await.ok()?;
+        // Another request may have checked the same key while this one waited
+        if let Some(id) = self.verified.read().unwrap().get(&digest) {
+            return Some(id.clone());
+        }
+        if self.rejected.lock().unwrap().digests.contains(&digest) {
+            return None;
+        }
+        let keys = self.clone();
+        let candidate = candidate.to_string();
+        tokio::task::spawn_blocking(move || keys.verify_argon2(&candidate, digest))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// Match against plaintext and sha256 entries, and argon2 entries the
+    /// key matched before
+    fn verify_cached(&self, candidate: &str, digest: &[u8; 32]) -> Option<String> {
+        let mut matched = None;
+        for key in self.keys.iter().filter(|k| !matches!(k, ApiKey::Argon2(_))) {
+            if key.verify(candidate) && matched.is_none() {
+                matched = Some(key.id());
+            }
+        }
+        matched.or_else(|| self.verified.read().unwrap().get(digest).cloned())
+    }
+
+    /// Run the argon2 check against every argon2 entry and remember the
+    /// outcome
+    fn verify_argon2(&self, candidate: &str, digest: [u8; 32]) -> Option<String> {
+        let id = self
+            .keys
+            .iter()
+            .find(|k| matches!(k, ApiKey::Argon2(_)) && k.verify(candidate))
+            .map(ApiKey::id);
+        match id {
+            Some(ref id) => {
+                self.verified.write().unwrap().insert(digest, id.clone());
+            }
+            None => self.rejected.lock().unwrap().insert(digest),
+        }
+        id
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::Instant,
+};
+
+use axum::body::Bytes;
+use lru::LruCache;
+use tokio::time::Duration;
+
+use crate::{config::CacheConfig, dedup::unwrap_batch_of_one};
+
+struct Entry {
+    body: Bytes,
+    inserted_at: Instant,
+    /// Network slot when the entry was stored, 0 if unknown
+    inserted_slot: u64,
+    /// Entry expires once the network advances this many slots
+    slot_ttl: Option<u64>,
+}
+
+pub enum CacheLookup {
+    Hit(Bytes),
+    /// An entry exists but has expired
+    Stale,
+    Miss,
+}
+
+impl CacheLookup {
+    /// Cache status as reported in the diagnostic header
+    pub fn status(&self) -> &'static str {
+        match self {
+            CacheLookup::Hit(_) => "HIT",
+            CacheLookup::Stale => "STALE",
+            CacheLookup::Miss => "MISS",
+        }
+    }
+}
+
+/// In-memory cache of JSON-RPC responses keyed by method and params. When
+/// full, the least recently used entry is dropped; expired entries are
+/// dropped when looked up.
+pub struct ResponseCache {
+    ttl: Duration,
+    slot_ttl: HashMap<String, u64>,
+    methods: HashSet<String>,
+    entries: Mutex<LruCache<String, Entry>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            slot_ttl: config.slot_ttl.clone(),
+            methods: config.methods.iter().cloned().collect(),
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN),
+            )),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.slot_ttl.is_empty() || !self.methods.is_empty()
+    }
+
+    /// Cache key for a request body, or None if the method isn't cacheable.
+    /// The `id` is left out so requests that differ only by id share an entry,
+    /// and a batch of one shares the key of the single request it wraps.
+    pub fn key(&self, rpc_method: &str, body: &[u8]) -> Option<String> {
+        if !self.slot_ttl.contains_key(rpc_method) && !self.methods.contains(rpc_method) {
+            return None;
+        }
+        let json = unwrap_batch_of_one(serde_json::from_slice(body).ok()?);
+        let params = json.get("params").cloned().unwrap_or_default();
+        Some(format!("{}:{}", rpc_method, params))
+    }
+
+    /// Whether an entry is still fresh. Slot-bound entries expire when the
+    /// network has advanced `slot_ttl` slots; when slot tracking is
+    /// unavailable they fall back to the time-based TTL.
+    fn is_fresh(&self, entry: &Entry, current_slot: u64) -> bool {
+        match entry.slot_ttl {
+            Some(slot_ttl) if entry.inserted_slot > 0 && current_slot > 0 => {
+                current_slot.saturating_sub(entry.inserted_slot) < slot_ttl
+            }
+            _ => entry.inserted_at.elapsed() < self.ttl,
+        }
+    }
+
+    pub fn lookup(&self, key: &str, current_slot: u64) -> CacheLookup {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if self.is_fresh(entry, current_slot) => {
+                CacheLookup::Hit(entry.body.clone())
+            }
+            Some(_) => {
+                entries.pop(key);
+                CacheLookup::Stale
+            }
+            None => CacheLookup::Miss,
+        }
+    }
+
+    /// Store a response. Batch-of-one responses are stored unwrapped so they
+    /// can answer the single form of the request too.
+    pub fn insert(&self, rpc_method: &str, key: String, body: Bytes, current_slot: u64) {
+        let body = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(json @ serde_json::Value::Array(_)) => {
+                serde_json::to_vec(&unwrap_batch_of_one(json))
+                    .map(Bytes::from)
+                    .unwrap_or(body)
+            }
+            _ => body,
+        };
+        self.entries.lock().unwrap().put(
+            key,
+            Entry {
+                body,
+                inserted_at: Instant::now(),
+                inserted_slot: current_slot,
+                slot_ttl: self.slot_ttl.get(rpc_method).copied(),
+            },
+        );
+    }
+}
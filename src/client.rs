@@ -0,0 +1,174 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, mem,
+    sync::RwLock,
+    time::Duration,
+};
+
+use axum::body::Body;
+use hyper_tls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use native_tls::Identity;
+use tokio_tungstenite::Connector;
+use tracing::{error, info};
+
+use crate::config::Config;
+
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+/// Connection and pool options applied to outbound connections
+#[derive(Debug, Clone, Copy)]
+pub struct ClientOptions {
+    pub nodelay: bool,
+    pub keepalive: Option<Duration>,
+    pub pool_max_idle_per_host: usize,
+    pub pool_idle_timeout: Duration,
+    /// Speak HTTP/2 only, negotiated via ALPN over TLS
+    pub http2_only: bool,
+}
+
+/// Load a PEM certificate chain and PKCS#8 PEM private key for mutual TLS
+pub fn load_identity(cert_path: &str, key_path: &str) -> Result<Identity, String> {
+    let cert = fs::read(cert_path)
+        .map_err(|e| format!("Failed to read client_cert '{}': {}", cert_path, e))?;
+    let key = fs::read(key_path)
+        .map_err(|e| format!("Failed to read client_key '{}': {}", key_path, e))?;
+    Identity::from_pkcs8(&cert, &key).map_err(|e| {
+        format!(
+            "Invalid client certificate '{}' or PKCS#8 key '{}': {}",
+            cert_path, key_path, e
+        )
+    })
+}
+
+/// Client certificates for every backend that has one configured
+fn client_identities(config: &Config) -> HashMap<String, Identity> {
+    config
+        .backends
+        .iter()
+        .filter_map(|backend| {
+            let (cert, key) = backend.client_identity(&config.proxy)?;
+            match load_identity(cert, key) {
+                Ok(identity) => Some((backend.label.clone(), identity)),
+                Err(e) => {
+                    error!("Backend {}: {}", backend.label, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn build_client(options: ClientOptions, identity: Option<&Identity>) -> HttpsClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_nodelay(options.nodelay);
+    http.set_keepalive(options.keepalive);
+
+    let mut tls = native_tls::TlsConnector::builder();
+    if options.http2_only {
+        tls.request_alpns(&["h2"]);
+    }
+    if let Some(identity) = identity {
+        tls.identity(identity.clone());
+    }
+    let tls = tls.build().expect("Failed to build TLS connector");
+
+    Client::builder(TokioExecutor::new())
+        .pool_max_idle_per_host(options.pool_max_idle_per_host)
+        .pool_idle_timeout(options.pool_idle_timeout)
+        .http2_only(options.http2_only)
+        .build(HttpsConnector::from((http, tls.into())))
+}
+
+/// One HTTP client (and therefore one connection pool) per backend, so a
+/// backend's pooled connections can be dropped without touching the others
+pub struct BackendClients {
+    options: ClientOptions,
+    /// Client certificates for backends that require mutual TLS
+    identities: RwLock<HashMap<String, Identity>>,
+    clients: RwLock<HashMap<String, HttpsClient>>,
+}
+
+impl BackendClients {
+    pub fn new(config: &Config, options: ClientOptions) -> Self {
+        let identities = client_identities(config);
+        let clients = config
+            .backends
+            .iter()
+            .map(|backend| {
+                let identity = identities.get(&backend.label);
+                (backend.label.clone(), build_client(options, identity))
+            })
+            .collect();
+        Self {
+            options,
+            identities: RwLock::new(identities),
+            clients: RwLock::new(clients),
+        }
+    }
+
+    fn build(&self, label: &str) -> HttpsClient {
+        build_client(self.options, self.identities.read().unwrap().get(label))
+    }
+
+    /// Re-read client certificates after a config reload. Backends whose
+    /// certificate was added, removed or possibly renewed get a fresh client.
+    pub fn reload(&self, config: &Config) {
+        let identities = client_identities(config);
+        let mut labels: HashSet<String> = identities.keys().cloned().collect();
+        let previous = mem::replace(&mut *self.identities.write().unwrap(), identities);
+        labels.extend(previous.into_keys());
+        let mut clients = self.clients.write().unwrap();
+        for label in labels {
+            if let Some(client) = clients.get_mut(&label) {
+                *client = self.build(&label);
+                info!("Reloaded client certificate for backend {}", label);
+            }
+        }
+    }
+
+    pub fn get(&self, label: &str) -> HttpsClient {
+        if let Some(client) = self.clients.read().unwrap().get(label) {
+            return client.clone();
+        }
+        self.clients
+            .write()
+            .unwrap()
+            .entry(label.to_string())
+            .or_insert_with(|| self.build(label))
+            .clone()
+    }
+
+    /// TLS connector for WebSocket connections to a backend that requires a
+    /// client certificate; `None` uses the default connector
+    pub fn ws_connector(&self, label: &str) -> Option<Connector> {
+        let identity = self.identities.read().unwrap().get(label)?.clone();
+        match native_tls::TlsConnector::builder()
+            .identity(identity)
+            .build()
+        {
+            Ok(tls) => Some(Connector::NativeTls(tls)),
+            Err(e) => {
+                error!(
+                    "Backend {}: failed to build WebSocket TLS connector: {}",
+                    label, e
+                );
+                None
+            }
+        }
+    }
+
+    /// Replace the backend's client with a fresh one. Idle pooled connections
+    /// are closed once the old client is dropped; requests already in flight
+    /// keep their connection until they complete.
+    pub fn evict(&self, label: &str) {
+        if let Some(client) = self.clients.write().unwrap().get_mut(label) {
+            *client = self.build(label);
+            info!("Dropped pooled connections to backend {}", label);
+        }
+    }
+}
@@ -1,29 +1,497 @@
-use std::{collections::HashMap, fs};
+use std::{collections::HashMap, fs, net::IpAddr, path::Path};
 
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use ipnet::IpNet;
 use serde::Deserialize;
 
+use crate::{access_log::LogTemplate, auth::ApiKey, client::load_identity};
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
+    /// Address to listen on; an IPv4 or IPv6 literal
+    #[serde(
+        default = "default_bind_addr",
+        deserialize_with = "deserialize_bind_addr"
+    )]
+    pub bind_addr: IpAddr,
     pub port: u16,
-    pub api_keys: Vec<String>,
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// File with one API key per line, merged with `api_keys`
+    pub api_keys_file: Option<String>,
+    /// Key required for `/admin/*` endpoints; they are disabled when unset
+    pub admin_api_key: Option<String>,
     pub backends: Vec<Backend>,
     #[serde(default)]
     pub method_routes: HashMap<String, String>,
+    /// When non-empty, only these RPC methods are accepted
+    #[serde(default)]
+    pub allowed_methods: Vec<String>,
+    /// RPC methods that are always rejected
+    #[serde(default)]
+    pub blocked_methods: Vec<String>,
+    /// Request rate limits for individual RPC methods
+    #[serde(default)]
+    pub method_rate_limits: HashMap<String, MethodRateLimit>,
+    /// Requests per second allowed for individual API keys, keyed by the key
+    /// (or its `key_id`, for hashed entries). Keys not listed are unlimited.
+    #[serde(default)]
+    pub rate_limits: HashMap<String, u32>,
+    /// Backends individual API keys are restricted to, keyed like
+    /// `rate_limits`. Keys not listed may use every backend.
+    #[serde(default)]
+    pub key_backends: HashMap<String, Vec<String>>,
+    /// Custom request log line, e.g. "{method} {backend} {status} {duration_ms}"
+    pub log_template: Option<String>,
+    /// Log output format; "json" emits one JSON object per line
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// Write logs to this file, rotated daily, instead of stdout
+    pub log_file: Option<String>,
+    /// With `log_file` set, also keep writing logs to stdout
+    #[serde(default)]
+    pub log_also_stdout: bool,
+    /// Number of recent requests kept for `/admin/recent` (0 disables)
+    #[serde(default = "default_recent_requests_size")]
+    pub recent_requests_size: usize,
     #[serde(default)]
     pub health_check: HealthCheckConfig,
     #[serde(default)]
     pub proxy: ProxyConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Serve HTTPS with this certificate instead of plain HTTP
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf certificate first
+    pub cert_path: String,
+    /// PEM private key for the certificate
+    pub key_path: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    /// Query parameter names a client API key is accepted under; all of them
+    /// are stripped before forwarding
+    pub key_params: Vec<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            key_params: vec!["api-key".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Accept backlog of the listening socket
+    pub tcp_backlog: u32,
+    /// Tokio worker threads; defaults to the number of CPUs
+    pub worker_threads: Option<usize>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp_backlog: 1024,
+            worker_threads: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Message of the JSON-RPC error returned to rate-limited clients
+    pub message: String,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            message: "Rate limit exceeded".to_string(),
+        }
+    }
+}
+
+fn default_bind_addr() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+fn deserialize_bind_addr<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<IpAddr, D::Error> {
+    let addr = String::deserialize(deserializer)?;
+    addr.parse().map_err(|_| {
+        serde::de::Error::custom(format!(
+            "bind_addr '{}' is not an IPv4 or IPv6 address (hostnames aren't supported)",
+            addr
+        ))
+    })
+}
+
+fn default_recent_requests_size() -> usize {
+    100
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct MethodRateLimit {
+    /// Requests per second allowed for the method
+    pub rps: u32,
+    /// Apply the limit to each API key separately instead of to all traffic
+    #[serde(default)]
+    pub per_key: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Time-based TTL, used when slot tracking is unavailable
+    pub ttl_secs: u64,
+    pub max_entries: usize,
+    /// Methods cached until the network advances this many slots
+    pub slot_ttl: HashMap<String, u64>,
+    /// Methods with immutable results, cached for `ttl_secs`
+    pub methods: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 5,
+            max_entries: 10_000,
+            slot_ttl: HashMap::new(),
+            methods: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
 pub struct ProxyConfig {
     pub timeout_secs: u64,
+    /// Random extra time (0..=jitter) added to each upstream timeout
+    pub timeout_jitter_ms: u64,
+    /// Upper bound on the time spent streaming a response body to the client
+    pub response_stream_timeout_secs: Option<u64>,
+    /// Answer JSON-RPC notifications (requests without an id) with 204 No Content
+    pub notification_no_content: bool,
+    /// File receiving requests for `dead_letter_methods` that could not be delivered
+    pub dead_letter_path: Option<String>,
+    pub dead_letter_methods: Vec<String>,
+    /// Methods whose successful responses must carry a non-null `result`
+    pub require_result_methods: Vec<String>,
+    /// How many times a failed request is retried on another backend
+    pub max_retries: u32,
+    /// Upstream HTTP status codes that count as a failed attempt and are retried
+    #[serde(alias = "retry_on_status")]
+    pub retry_status_codes: Vec<u16>,
+    /// Pin all requests on one client connection to the same backend
+    pub connection_affinity: bool,
+    /// Forget a connection's pinned backend after this much inactivity
+    pub connection_affinity_idle_secs: u64,
+    /// Fraction (0.0-1.0) of requests whose bodies are logged for debugging
+    pub body_sample_rate: f64,
+    /// Fraction (0.0-1.0) of each backend's `max_rps` held back for bursts;
+    /// above the rest, traffic spills to other backends
+    pub max_rps_headroom: f64,
+    /// Disable failover while the aggregate failure rate is too high
+    pub global_failover_trip: Option<FailoverTripConfig>,
+    /// Answer repeated `sendTransaction` submissions of the same transaction
+    /// within this window with the first response
+    pub send_transaction_dedup_secs: Option<u64>,
+    /// Periodically send a lightweight request to each healthy backend so its
+    /// pooled connections stay open between bursts
+    pub keepalive_warm_interval_secs: Option<u64>,
+    /// What to do when a backend answers with a 3xx redirect
+    pub redirect_policy: RedirectPolicy,
+    /// Maximum redirects followed per attempt under the `follow` policy
+    pub max_redirects: u32,
+    /// Restrict the `follow` policy to these methods (empty means all);
+    /// redirects for other methods fail over
+    pub follow_redirect_methods: Vec<String>,
+    /// How often backends with an `srv` name are re-resolved
+    pub srv_refresh_secs: u64,
+    /// Set TCP_NODELAY on client and backend connections
+    pub tcp_nodelay: bool,
+    /// TCP keepalive interval for backend connections (0 disables)
+    pub tcp_keepalive_secs: u64,
+    /// Idle pooled connections kept open per backend host
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle pooled connection is kept before being closed
+    pub pool_idle_timeout_secs: u64,
+    /// Use HTTP/2 only for backend connections
+    pub http2_only: bool,
+    /// Answer `rpc.discover` locally with an OpenRPC document
+    pub answer_rpc_discover: bool,
+    /// Methods listed by `rpc.discover` in addition to routed and limited ones
+    pub discover_methods: Vec<String>,
+    /// Scale backend weights by their recent success rate
+    pub adaptive_weight: Option<AdaptiveWeightConfig>,
+    /// Consecutive proxy failures after which a backend's circuit opens
+    pub breaker_threshold: Option<u32>,
+    /// How long an open circuit excludes the backend before a trial request
+    pub breaker_cooldown_secs: u64,
+    /// Strip insignificant whitespace from JSON request bodies before forwarding
+    pub compact_request_body: bool,
+    /// Add an `X-Proxy-Diag` header with backend, cache status, attempts and
+    /// upstream duration to responses
+    pub diag_header: bool,
+    /// How long a draining backend takes to ramp its weight down to zero
+    pub drain_grace_secs: u64,
+    /// How long a backend takes to ramp up to its full weight after it turns
+    /// healthy or is added by a reload
+    pub warmup_secs: Option<u64>,
+    /// When no backend is healthy, keep retrying selection for up to this
+    /// long before responding 503
+    pub wait_for_backend_ms: Option<u64>,
+    /// Reject requests that aren't well-formed JSON-RPC 2.0 with -32600
+    /// instead of forwarding them
+    pub strict_jsonrpc: bool,
+    /// How a backend is chosen among the healthy candidates
+    pub strategy: Strategy,
+    /// Smoothing factor (0.0-1.0] of the per-backend latency EWMA; higher
+    /// reacts faster to recent responses
+    pub latency_alpha: f64,
+    /// Position of the param hashed by the "consistent_hash" strategy, per
+    /// method; an array param is hashed by its first element. Requests for
+    /// other methods are selected by weight.
+    pub hash_key_params: HashMap<String, usize>,
+    /// Requests per second allowed from a single client IP
+    pub ip_rate_limit: Option<u32>,
+    /// Proxies whose `X-Forwarded-For` header is trusted to carry the
+    /// client IP
+    pub trusted_proxies: Vec<IpNet>,
+    /// Append the client's address to `X-Forwarded-For` and set `X-Real-IP`
+    /// on forwarded requests
+    pub forward_client_ip: bool,
+    /// Serve Prometheus metrics at `GET /metrics`
+    pub metrics_enabled: bool,
+    /// Compress responses (gzip or brotli, per the client's Accept-Encoding)
+    /// that the upstream didn't compress
+    pub compress_responses: bool,
+    /// Responses smaller than this many bytes are never compressed
+    pub compress_min_bytes: u16,
+    /// Origins allowed to call the proxy from a browser; `*` allows any.
+    /// CORS is disabled when empty.
+    pub cors_allowed_origins: Vec<String>,
+    /// How long to wait for open connections to finish on shutdown
+    pub shutdown_timeout_secs: u64,
+    /// Largest request body accepted; bigger requests get a 413
+    pub max_body_bytes: usize,
+    /// Largest upstream response read into memory for result validation,
+    /// caching, dedup or body sampling; bigger responses fail with a 502
+    pub max_response_bytes: usize,
+    /// Accept `GET ?method=` requests for the methods in `get_methods`
+    pub allow_get: bool,
+    /// Methods that may be called with GET when `allow_get` is set
+    pub get_methods: Vec<String>,
+    /// When non-empty, the only client headers forwarded upstream, besides
+    /// Host, Content-Type, Content-Length and X-Request-Id
+    pub forward_headers: Vec<String>,
+    /// Client headers never forwarded upstream
+    pub strip_headers: Vec<String>,
+    /// Let clients pin a request to a backend with `X-Backend: <label>`
+    pub allow_backend_override: bool,
+    /// Deadline for a whole request, from accepting it (including reading
+    /// its body) to the upstream response headers
+    pub total_timeout_secs: Option<u64>,
+    /// Default client certificate (PEM) presented to backends over mutual TLS
+    pub client_cert: Option<String>,
+    /// PKCS#8 PEM private key for `client_cert`
+    pub client_key: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// Random, proportional to backend weight
+    #[default]
+    Weighted,
+    /// Fewest in-flight requests, ties broken by weight
+    LeastConnections,
+    /// Fixed rotation with each backend repeated `weight` times
+    RoundRobin,
+    /// Random, proportional to weight divided by average response latency
+    Latency,
+    /// Two weighted random picks; the one with fewer in-flight requests wins
+    P2c,
+    /// Requests for the same account or signature go to the same backend,
+    /// placed on a hash ring by weight
+    ConsistentHash,
+    /// Weighted random within the lowest `priority` tier that has an
+    /// available backend
+    Priority,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AdaptiveWeightConfig {
+    /// Time for an observation's influence on the success rate to halve
+    pub half_life_secs: f64,
+    /// Lowest fraction (0.0-1.0) of its configured weight a backend keeps
+    pub weight_floor: f64,
+    /// Error rate (0.0-1.0) tolerated before the weight starts dropping
+    pub error_rate_threshold: f64,
+    /// Average latency above which the weight drops in proportion
+    pub latency_threshold_ms: Option<f64>,
+}
+
+impl Default for AdaptiveWeightConfig {
+    fn default() -> Self {
+        Self {
+            half_life_secs: 30.0,
+            weight_floor: 0.1,
+            error_rate_threshold: 0.0,
+            latency_threshold_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with request details as separate fields
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectPolicy {
+    /// Treat the redirect as a failed attempt and fail over
+    #[default]
+    Failover,
+    /// Follow the redirect upstream with the same method and body
+    Follow,
+    /// Pass the redirect through to the client unchanged
+    Forward,
 }
 
 impl Default for ProxyConfig {
     fn default() -> Self {
-        Self { timeout_secs: 30 }
+        Self {
+            timeout_secs: 30,
+            timeout_jitter_ms: 0,
+            response_stream_timeout_secs: None,
+            notification_no_content: false,
+            dead_letter_path: None,
+            dead_letter_methods: vec!["sendTransaction".to_string()],
+            require_result_methods: Vec::new(),
+            max_retries: 0,
+            retry_status_codes: vec![502, 503, 504],
+            connection_affinity: false,
+            connection_affinity_idle_secs: 90,
+            body_sample_rate: 0.0,
+            max_rps_headroom: 0.0,
+            global_failover_trip: None,
+            send_transaction_dedup_secs: None,
+            keepalive_warm_interval_secs: None,
+            redirect_policy: RedirectPolicy::Failover,
+            max_redirects: 3,
+            follow_redirect_methods: Vec::new(),
+            srv_refresh_secs: 30,
+            tcp_nodelay: true,
+            tcp_keepalive_secs: 60,
+            pool_max_idle_per_host: 64,
+            pool_idle_timeout_secs: 90,
+            http2_only: false,
+            answer_rpc_discover: false,
+            discover_methods: Vec::new(),
+            adaptive_weight: None,
+            breaker_threshold: None,
+            breaker_cooldown_secs: 30,
+            compact_request_body: false,
+            diag_header: false,
+            drain_grace_secs: 30,
+            warmup_secs: None,
+            wait_for_backend_ms: None,
+            strict_jsonrpc: false,
+            strategy: Strategy::Weighted,
+            latency_alpha: 0.2,
+            hash_key_params: [
+                "getAccountInfo",
+                "getBalance",
+                "getMultipleAccounts",
+                "getProgramAccounts",
+                "getSignaturesForAddress",
+                "getTokenAccountBalance",
+                "getTokenAccountsByOwner",
+                "getTransaction",
+            ]
+            .into_iter()
+            .map(|method| (method.to_string(), 0))
+            .collect(),
+            ip_rate_limit: None,
+            trusted_proxies: Vec::new(),
+            forward_client_ip: false,
+            metrics_enabled: false,
+            compress_responses: false,
+            compress_min_bytes: 1024,
+            cors_allowed_origins: Vec::new(),
+            shutdown_timeout_secs: 30,
+            max_body_bytes: 10 * 1024 * 1024,
+            max_response_bytes: 100 * 1024 * 1024,
+            allow_get: false,
+            get_methods: [
+                "getHealth",
+                "getVersion",
+                "getSlot",
+                "getBlockHeight",
+                "getEpochInfo",
+                "getLatestBlockhash",
+                "getBalance",
+            ]
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+            forward_headers: Vec::new(),
+            strip_headers: Vec::new(),
+            allow_backend_override: false,
+            total_timeout_secs: None,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct FailoverTripConfig {
+    /// Failure rate (0.0-1.0) across all backends above which failover stops
+    pub failure_rate: f64,
+    /// Sliding window over which the failure rate is measured
+    pub window_secs: u64,
+    /// Minimum requests in the window before the trip can engage
+    pub min_requests: u64,
+}
+
+impl Default for FailoverTripConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            window_secs: 10,
+            min_requests: 20,
+        }
     }
 }
 
@@ -35,6 +503,20 @@ pub struct HealthCheckConfig {
     pub method: String,
     pub consecutive_failures_threshold: u32,
     pub consecutive_successes_threshold: u32,
+    /// Optional file used to persist backend health across restarts
+    pub state_file: Option<String>,
+    /// Probe all backends once at startup before accepting traffic
+    pub probe_before_listen: bool,
+    /// Minimum number of backends that must pass the startup probe
+    pub min_healthy: usize,
+    /// Maximum number of startup probes in flight at once
+    pub probe_concurrency: usize,
+    /// Probes fail when the backend's slot is more than this many slots
+    /// behind the highest slot observed across backends
+    pub max_slot_lag: Option<u64>,
+    /// Probe unhealthy backends at an interval that doubles with each failed
+    /// probe up to this cap, and recheck quickly while health is changing
+    pub backoff_max_secs: Option<u64>,
 }
 
 impl Default for HealthCheckConfig {
@@ -45,6 +527,12 @@ impl Default for HealthCheckConfig {
             method: "getSlot".to_string(),
             consecutive_failures_threshold: 3,
             consecutive_successes_threshold: 2,
+            state_file: None,
+            probe_before_listen: false,
+            min_healthy: 1,
+            probe_concurrency: 16,
+            max_slot_lag: None,
+            backoff_max_secs: None,
         }
     }
 }
@@ -55,6 +543,199 @@ pub struct Backend {
     pub url: String,
     pub weight: u32,
     pub ws_url: Option<String>,
+    /// DNS SRV name resolved periodically to the endpoints serving this
+    /// backend; `url` then only supplies the scheme and path
+    pub srv: Option<String>,
+    /// Provider/region tag; retries prefer a backend in a different zone
+    pub zone: Option<String>,
+    /// Tier for the "priority" strategy; lower tiers are used first
+    #[serde(default)]
+    pub priority: u32,
+    /// Maximum requests per second the provider accepts from this proxy
+    pub max_rps: Option<u32>,
+    /// Overrides `proxy.max_rps_headroom` for this backend
+    pub max_rps_headroom: Option<f64>,
+    /// Forward the client's Host header instead of rewriting it to the backend's
+    #[serde(default)]
+    pub preserve_host: bool,
+    /// Overrides `proxy.body_sample_rate` for this backend
+    pub body_sample_rate: Option<f64>,
+    /// Params to remove from forwarded requests, keyed by RPC method
+    #[serde(default)]
+    pub strip_params: HashMap<String, Vec<String>>,
+    /// Overrides `health_check.consecutive_failures_threshold` for this backend
+    pub consecutive_failures_threshold: Option<u32>,
+    /// Overrides `health_check.consecutive_successes_threshold` for this backend
+    pub consecutive_successes_threshold: Option<u32>,
+    /// Overrides `health_check.method`, for backends that aren't Solana nodes
+    pub health_method: Option<String>,
+    /// When false the backend is never probed and always treated as healthy
+    #[serde(default = "default_true")]
+    pub health_check_enabled: bool,
+    /// Headers added to every request sent to this backend, e.g. a provider's
+    /// auth header. Never logged.
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// `name=value` query parameter appended to every request sent to this
+    /// backend, for providers that take their API key in the URL. Never logged.
+    pub api_key_param: Option<String>,
+    /// Client certificate (PEM) for backends that require mutual TLS;
+    /// overrides `proxy.client_cert`
+    pub client_cert: Option<String>,
+    /// PKCS#8 PEM private key for `client_cert`
+    pub client_key: Option<String>,
+}
+
+impl Backend {
+    /// Rate below `max_rps` that normal traffic is capped at, when headroom
+    /// is reserved
+    pub fn reserved_rps(&self, proxy: &ProxyConfig) -> Option<f64> {
+        let headroom = self.max_rps_headroom.unwrap_or(proxy.max_rps_headroom);
+        self.max_rps
+            .filter(|_| headroom > 0.0)
+            .map(|rps| rps as f64 * (1.0 - headroom))
+    }
+
+    pub fn failures_threshold(&self, health_config: &HealthCheckConfig) -> u32 {
+        self.consecutive_failures_threshold
+            .unwrap_or(health_config.consecutive_failures_threshold)
+    }
+
+    /// Certificate and key paths presented to this backend, falling back to
+    /// the proxy-wide default
+    pub fn client_identity<'a>(&'a self, proxy: &'a ProxyConfig) -> Option<(&'a str, &'a str)> {
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            _ => match (&proxy.client_cert, &proxy.client_key) {
+                (Some(cert), Some(key)) => Some((cert, key)),
+                _ => None,
+            },
+        }
+    }
+
+    /// `url` with the provider's `api_key_param`, if any, added to its query
+    pub fn authenticated_url(&self, url: &str) -> String {
+        match self.api_key_param {
+            Some(ref param) => {
+                let separator = if url.contains('?') { '&' } else { '?' };
+                format!("{}{}{}", url, separator, param)
+            }
+            None => url.to_string(),
+        }
+    }
+
+    /// Add the provider's configured `headers` to an upstream request
+    pub fn insert_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                headers.insert(name, value);
+            }
+        }
+    }
+
+    /// Remove the provider's configured `headers`, so they aren't sent to a
+    /// host other than the provider's
+    pub fn remove_headers(&self, headers: &mut HeaderMap) {
+        for name in self.headers.keys() {
+            headers.remove(name.as_str());
+        }
+    }
+
+    /// JSON-RPC method used to probe this backend
+    pub fn health_method<'a>(&'a self, health_config: &'a HealthCheckConfig) -> &'a str {
+        self.health_method
+            .as_deref()
+            .unwrap_or(&health_config.method)
+    }
+
+    pub fn successes_threshold(&self, health_config: &HealthCheckConfig) -> u32 {
+        self.consecutive_successes_threshold
+            .unwrap_or(health_config.consecutive_successes_threshold)
+    }
+}
+
+/// Whether `key_or_id` is one of the configured API keys or a key's id
+fn is_configured_key(api_keys: &[ApiKey], key_or_id: &str) -> bool {
+    api_keys
+        .iter()
+        .any(|api_key| api_key.id() == key_or_id || api_key.verify(key_or_id))
+}
+
+/// Replace `${NAME}` placeholders in every string value with the environment
+/// variable `NAME`, so secrets can be kept out of the config file. A variable
+/// that isn't set is an error.
+fn expand_env_vars(value: &mut toml::Value) -> Result<(), String> {
+    match value {
+        toml::Value::String(s) if s.contains("${") => *s = expand_env_string(s)?,
+        toml::Value::Array(items) => {
+            for item in items {
+                expand_env_vars(item)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, item) in table.iter_mut() {
+                expand_env_vars(item)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn expand_env_string(s: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            return Err(format!(
+                "Unterminated ${{ placeholder in config value '{}'",
+                s
+            ));
+        };
+        let name = &after[..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "Invalid environment variable name '{}' in config",
+                name
+            ));
+        }
+        let expanded = std::env::var(name).map_err(|_| {
+            format!(
+                "Environment variable {} referenced in config is not set",
+                name
+            )
+        })?;
+        out.push_str(&expanded);
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Read `api_keys_file`: one key (plaintext or hashed, as in `api_keys`) per
+/// line, ignoring blank lines and `#` comments
+fn load_api_keys_file(path: &str) -> Result<Vec<ApiKey>, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read api_keys_file '{}': {}", path, e))?;
+    let mut keys = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = ApiKey::try_from(line.to_string())
+            .map_err(|e| format!("api_keys_file '{}' line {}: {}", path, number + 1, e))?;
+        keys.push(key);
+    }
+    if keys.is_empty() {
+        return Err(format!("api_keys_file '{}' contains no keys", path));
+    }
+    Ok(keys)
 }
 
 pub fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Error>> {
@@ -64,12 +745,26 @@ pub fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Erro
 
     // Read TOML file directly to preserve case sensitivity
     let contents = fs::read_to_string(config_path)?;
-    let config: Config = toml::from_str(&contents)?;
+    let mut value: toml::Value = toml::from_str(&contents)?;
+    expand_env_vars(&mut value)?;
+    let mut config: Config = value.try_into()?;
+    if let Some(ref path) = config.api_keys_file {
+        let keys = load_api_keys_file(path)?;
+        config.api_keys.extend(keys);
+    }
 
     // Validation
     if config.api_keys.is_empty() {
         return Err("At least one API key must be configured".into());
     }
+    if let Some(ref admin_key) = config.admin_api_key {
+        if admin_key.is_empty() {
+            return Err("admin_api_key must not be empty".into());
+        }
+        if config.api_keys.iter().any(|key| key.verify(admin_key)) {
+            return Err("admin_api_key must differ from client API keys".into());
+        }
+    }
     if config.backends.is_empty() {
         return Err("At least one backend must be configured".into());
     }
@@ -86,18 +781,325 @@ pub fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Erro
         return Err("Duplicate backend labels found in configuration".into());
     }
 
+    if config.proxy.client_cert.is_some() != config.proxy.client_key.is_some() {
+        return Err("Proxy client_cert and client_key must be set together".into());
+    }
+
     for backend in &config.backends {
+        if backend.client_cert.is_some() != backend.client_key.is_some() {
+            return Err(format!(
+                "Backend '{}' client_cert and client_key must be set together",
+                backend.label
+            )
+            .into());
+        }
+        if let Some((cert, key)) = backend.client_identity(&config.proxy) {
+            load_identity(cert, key).map_err(|e| format!("Backend '{}': {}", backend.label, e))?;
+        }
         if backend.weight == 0 {
             return Err(format!("Backend '{}' has invalid weight 0", backend.label).into());
         }
         if backend.label.is_empty() {
             return Err(format!("Backend with URL '{}' has empty label", backend.url).into());
         }
+        let uri = backend.url.parse::<axum::http::Uri>().map_err(|e| {
+            format!(
+                "Backend '{}' has invalid url '{}': {}",
+                backend.label, backend.url, e
+            )
+        })?;
+        if uri.scheme().is_none() || uri.host().is_none() {
+            return Err(format!(
+                "Backend '{}' url '{}' must be absolute, e.g. https://host/path",
+                backend.label, backend.url
+            )
+            .into());
+        }
+        if backend.consecutive_failures_threshold == Some(0)
+            || backend.consecutive_successes_threshold == Some(0)
+        {
+            return Err(
+                format!("Backend '{}' health thresholds must be > 0", backend.label).into(),
+            );
+        }
+        if let Some(rate) = backend.body_sample_rate {
+            if !(0.0..=1.0).contains(&rate) {
+                return Err(format!(
+                    "Backend '{}' body_sample_rate must be between 0.0 and 1.0",
+                    backend.label
+                )
+                .into());
+            }
+        }
+        if let Some(headroom) = backend.max_rps_headroom {
+            if !(0.0..1.0).contains(&headroom) {
+                return Err(format!(
+                    "Backend '{}' max_rps_headroom must be at least 0.0 and below 1.0",
+                    backend.label
+                )
+                .into());
+            }
+        }
+        if backend.max_rps == Some(0) {
+            return Err(format!("Backend '{}' has invalid max_rps 0", backend.label).into());
+        }
+        for (name, value) in &backend.headers {
+            if HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+            {
+                return Err(format!(
+                    "Backend '{}' has an invalid header '{}'",
+                    backend.label, name
+                )
+                .into());
+            }
+        }
+        if let Some(ref param) = backend.api_key_param {
+            if param
+                .split_once('=')
+                .is_none_or(|(name, _)| name.is_empty())
+                || param.contains(['&', '?', '#'])
+            {
+                return Err(format!(
+                    "Backend '{}' api_key_param must be a single name=value pair",
+                    backend.label
+                )
+                .into());
+            }
+        }
+    }
+
+    if let Some(ref path) = config.log_file {
+        if Path::new(path).file_name().is_none() {
+            return Err(format!("log_file '{}' must name a file", path).into());
+        }
+    }
+
+    if let Some(ref tls) = config.tls {
+        for (name, path) in [("cert_path", &tls.cert_path), ("key_path", &tls.key_path)] {
+            if !Path::new(path).is_file() {
+                return Err(format!("TLS {} '{}' is not a readable file", name, path).into());
+            }
+        }
+    }
+
+    if config.auth.key_params.is_empty() {
+        return Err("Auth key_params must not be empty".into());
+    }
+    if config.auth.key_params.iter().any(|name| name.is_empty()) {
+        return Err("Auth key_params must not contain empty names".into());
+    }
+
+    if config.server.tcp_backlog == 0 {
+        return Err("Server tcp_backlog must be > 0".into());
+    }
+    if config.server.worker_threads == Some(0) {
+        return Err("Server worker_threads must be > 0".into());
+    }
+
+    if config.health_check.probe_concurrency == 0 {
+        return Err("Health check probe_concurrency must be > 0".into());
+    }
+    if config.health_check.min_healthy > config.backends.len() {
+        return Err(format!(
+            "Health check min_healthy ({}) exceeds the number of backends ({})",
+            config.health_check.min_healthy,
+            config.backends.len()
+        )
+        .into());
+    }
+    if let Some(max) = config.health_check.backoff_max_secs {
+        if max < config.health_check.interval_secs {
+            return Err(format!(
+                "Health check backoff_max_secs ({}) must be at least interval_secs ({})",
+                max, config.health_check.interval_secs
+            )
+            .into());
+        }
+    }
+
+    for (method, limit) in &config.method_rate_limits {
+        if limit.rps == 0 {
+            return Err(format!("Method rate limit for '{}' must have rps > 0", method).into());
+        }
+    }
+
+    for (key, rps) in &config.rate_limits {
+        if *rps == 0 {
+            return Err(format!("Rate limit for API key '{}' must be > 0", key).into());
+        }
+        if !is_configured_key(&config.api_keys, key) {
+            return Err(format!(
+                "Rate limit for '{}' does not match any configured API key or key_id",
+                key
+            )
+            .into());
+        }
+    }
+    for (key, labels) in &config.key_backends {
+        if !is_configured_key(&config.api_keys, key) {
+            return Err(format!(
+                "key_backends entry '{}' does not match any configured API key or key_id",
+                key
+            )
+            .into());
+        }
+        if labels.is_empty() {
+            return Err(format!(
+                "key_backends entry '{}' must list at least one backend",
+                key
+            )
+            .into());
+        }
+        if let Some(label) = labels.iter().find(|l| !backend_labels.contains_key(*l)) {
+            return Err(format!(
+                "key_backends entry '{}' references unknown backend label '{}'",
+                key, label
+            )
+            .into());
+        }
+    }
+
+    if let Some(method) = config
+        .blocked_methods
+        .iter()
+        .find(|method| config.allowed_methods.contains(method))
+    {
+        return Err(format!(
+            "Method '{}' is in both allowed_methods and blocked_methods",
+            method
+        )
+        .into());
     }
 
+    if let Some(ref template) = config.log_template {
+        LogTemplate::parse(template)?;
+    }
+
+    if config.proxy.wait_for_backend_ms == Some(0) {
+        return Err("Proxy wait_for_backend_ms must be > 0".into());
+    }
+    if config.proxy.warmup_secs == Some(0) {
+        return Err("Proxy warmup_secs must be > 0".into());
+    }
+    if config.proxy.total_timeout_secs == Some(0) {
+        return Err("Proxy total_timeout_secs must be > 0".into());
+    }
+    if config.proxy.max_body_bytes == 0 {
+        return Err("Proxy max_body_bytes must be > 0".into());
+    }
+    if config.proxy.max_response_bytes == 0 {
+        return Err("Proxy max_response_bytes must be > 0".into());
+    }
     if config.proxy.timeout_secs == 0 {
         return Err("Proxy timeout_secs must be > 0".into());
     }
+    if config.proxy.connection_affinity && config.proxy.connection_affinity_idle_secs == 0 {
+        return Err("Proxy connection_affinity_idle_secs must be > 0".into());
+    }
+    if !(0.0..=1.0).contains(&config.proxy.body_sample_rate) {
+        return Err("Proxy body_sample_rate must be between 0.0 and 1.0".into());
+    }
+    if !(0.0..1.0).contains(&config.proxy.max_rps_headroom) {
+        return Err("Proxy max_rps_headroom must be at least 0.0 and below 1.0".into());
+    }
+    for code in &config.proxy.retry_status_codes {
+        if !(400..=599).contains(code) {
+            return Err(format!(
+                "Proxy retry_status_codes contains {}, expected a 4xx or 5xx status",
+                code
+            )
+            .into());
+        }
+    }
+    if let Some(ref trip) = config.proxy.global_failover_trip {
+        if !(0.0..=1.0).contains(&trip.failure_rate) {
+            return Err("global_failover_trip failure_rate must be between 0.0 and 1.0".into());
+        }
+        if trip.window_secs == 0 {
+            return Err("global_failover_trip window_secs must be > 0".into());
+        }
+    }
+    if !(config.proxy.latency_alpha > 0.0 && config.proxy.latency_alpha <= 1.0) {
+        return Err("Proxy latency_alpha must be in (0.0, 1.0]".into());
+    }
+    let origins = &config.proxy.cors_allowed_origins;
+    if origins.len() > 1 && origins.iter().any(|origin| origin == "*") {
+        return Err(
+            "Proxy cors_allowed_origins must be either [\"*\"] or a list of origins".into(),
+        );
+    }
+    if let Some(origin) = origins
+        .iter()
+        .find(|origin| HeaderValue::from_str(origin).is_err())
+    {
+        return Err(format!("Invalid CORS origin '{}'", origin).into());
+    }
+    if let Some(name) = config
+        .proxy
+        .forward_headers
+        .iter()
+        .chain(&config.proxy.strip_headers)
+        .find(|name| HeaderName::from_bytes(name.as_bytes()).is_err())
+    {
+        return Err(format!("Invalid header name '{}'", name).into());
+    }
+    if config.proxy.pool_max_idle_per_host == 0 {
+        return Err("Proxy pool_max_idle_per_host must be > 0".into());
+    }
+    if config.proxy.pool_idle_timeout_secs == 0 {
+        return Err("Proxy pool_idle_timeout_secs must be > 0".into());
+    }
+    if config.proxy.ip_rate_limit == Some(0) {
+        return Err("Proxy ip_rate_limit must be > 0".into());
+    }
+    if config.proxy.breaker_threshold == Some(0) {
+        return Err("Proxy breaker_threshold must be > 0".into());
+    }
+    if config.proxy.breaker_cooldown_secs == 0 {
+        return Err("Proxy breaker_cooldown_secs must be > 0".into());
+    }
+    if let Some(ref adaptive) = config.proxy.adaptive_weight {
+        if adaptive.half_life_secs <= 0.0 {
+            return Err("adaptive_weight half_life_secs must be > 0".into());
+        }
+        if !(0.0..=1.0).contains(&adaptive.weight_floor) || adaptive.weight_floor == 0.0 {
+            return Err("adaptive_weight weight_floor must be in (0.0, 1.0]".into());
+        }
+        if !(0.0..1.0).contains(&adaptive.error_rate_threshold) {
+            return Err("adaptive_weight error_rate_threshold must be in [0.0, 1.0)".into());
+        }
+        if adaptive.latency_threshold_ms.is_some_and(|ms| ms <= 0.0) {
+            return Err("adaptive_weight latency_threshold_ms must be > 0".into());
+        }
+    }
+    if config.proxy.send_transaction_dedup_secs == Some(0) {
+        return Err("Proxy send_transaction_dedup_secs must be > 0".into());
+    }
+    if config.proxy.redirect_policy == RedirectPolicy::Follow && config.proxy.max_redirects == 0 {
+        return Err("Proxy max_redirects must be > 0 when redirect_policy is \"follow\"".into());
+    }
+    if config.proxy.srv_refresh_secs == 0 {
+        return Err("Proxy srv_refresh_secs must be > 0".into());
+    }
+    if config.proxy.keepalive_warm_interval_secs == Some(0) {
+        return Err("Proxy keepalive_warm_interval_secs must be > 0".into());
+    }
+    if config.proxy.response_stream_timeout_secs == Some(0) {
+        return Err("Proxy response_stream_timeout_secs must be > 0".into());
+    }
+
+    if config.cache.max_entries == 0 {
+        return Err("Cache max_entries must be > 0".into());
+    }
+    for (method, slots) in &config.cache.slot_ttl {
+        if *slots == 0 {
+            return Err(format!("Cache slot_ttl for '{}' must be > 0", method).into());
+        }
+    }
+    if !config.cache.methods.is_empty() && config.cache.ttl_secs == 0 {
+        return Err("Cache ttl_secs must be > 0 when cache methods are configured".into());
+    }
 
     // Validate method_routes reference valid backend labels
     for (method, label) in &config.method_routes {
@@ -112,3 +1114,38 @@ pub fn load_config(config_path: &str) -> Result<Config, Box<dyn std::error::Erro
 
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_env_string_substitutes_set_variables() {
+        std::env::set_var("SOL_RPC_ROUTER_TEST_KEY", "secret");
+        assert_eq!(
+            expand_env_string("https://rpc.example/?api-key=${SOL_RPC_ROUTER_TEST_KEY}&x=1")
+                .unwrap(),
+            "https://rpc.example/?api-key=secret&x=1"
+        );
+        assert_eq!(
+            expand_env_string("${SOL_RPC_ROUTER_TEST_KEY}${SOL_RPC_ROUTER_TEST_KEY}").unwrap(),
+            "secretsecret"
+        );
+    }
+
+    #[test]
+    fn expand_env_string_rejects_unset_variables() {
+        std::env::remove_var("SOL_RPC_ROUTER_TEST_UNSET");
+        let err = expand_env_string("${SOL_RPC_ROUTER_TEST_UNSET}").unwrap_err();
+        assert!(err.contains("SOL_RPC_ROUTER_TEST_UNSET"), "{}", err);
+    }
+
+    #[test]
+    fn expand_env_string_leaves_plain_strings_alone() {
+        assert_eq!(
+            expand_env_string("no placeholders").unwrap(),
+            "no placeholders"
+        );
+        assert!(expand_env_string("${UNTERMINATED").is_err());
+    }
+}
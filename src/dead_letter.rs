@@ -0,0 +1,72 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tracing::error;
+
+/// Append-only JSON lines log of requests that could not be delivered to any
+/// backend, kept so they can be inspected and resubmitted by hand.
+pub struct DeadLetterLog {
+    path: String,
+    file: Mutex<File>,
+}
+
+#[derive(Serialize)]
+struct DeadLetterEntry<'a> {
+    timestamp: u64,
+    rpc_method: &'a str,
+    backend: Option<&'a str>,
+    reason: &'a str,
+    payload: serde_json::Value,
+}
+
+impl DeadLetterLog {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        // Payloads contain signed transactions, so keep the file owner-only
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        Ok(Self {
+            path: path.to_string(),
+            file: Mutex::new(options.open(path)?),
+        })
+    }
+
+    pub fn record(&self, rpc_method: &str, backend: Option<&str>, reason: &str, body: &[u8]) {
+        let payload = serde_json::from_slice(body).unwrap_or_else(|_| {
+            serde_json::Value::String(String::from_utf8_lossy(body).into_owned())
+        });
+        let entry = DeadLetterEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            rpc_method,
+            backend,
+            reason,
+            payload,
+        };
+
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize dead-letter entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(&line) {
+            error!("Failed to write dead-letter entry to {}: {}", self.path, e);
+        }
+    }
+}
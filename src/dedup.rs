@@ -0,0 +1,73 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use axum::body::Bytes;
+use sha2::{Digest, Sha256};
+use tokio::time::Duration;
+
+/// Remembers successful `sendTransaction` responses for a short window so a
+/// client re-submitting the same transaction gets the first response back
+/// instead of the transaction being forwarded again.
+pub struct TransactionDedup {
+    window: Duration,
+    entries: Mutex<HashMap<String, (Instant, Bytes)>>,
+}
+
+impl TransactionDedup {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Dedup key for a `sendTransaction` request body: a hash of the encoded
+    /// transaction only, so the same transaction sent with different options
+    /// (`skipPreflight`, `encoding`, ...) is treated as a duplicate
+    pub fn key(body: &[u8]) -> Option<String> {
+        let json = unwrap_batch_of_one(serde_json::from_slice(body).ok()?);
+        let transaction = json.get("params")?.get(0)?.as_str()?;
+        Some(format!("{:x}", Sha256::digest(transaction.as_bytes())))
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(stored, _)| stored.elapsed() < self.window)
+            .map(|(_, body)| body.clone())
+    }
+
+    pub fn insert(&self, key: String, body: Bytes) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.window);
+        entries.insert(key, (Instant::now(), body));
+    }
+}
+
+/// Treat a batch of one JSON-RPC request (or response) as the single object
+/// it wraps, so both forms share dedup and cache keys
+pub fn unwrap_batch_of_one(json: serde_json::Value) -> serde_json::Value {
+    match json {
+        serde_json::Value::Array(mut items) if items.len() == 1 => items.remove(0),
+        other => other,
+    }
+}
+
+/// Replace the `id` of a cached JSON-RPC response with the id of the request
+/// being answered. A batch-of-one request gets the response wrapped in a
+/// batch of one.
+pub fn rewrite_response_id(response: &[u8], request: &[u8]) -> Option<Vec<u8>> {
+    let request = serde_json::from_slice::<serde_json::Value>(request).ok()?;
+    let is_batch = request.is_array();
+    let request = unwrap_batch_of_one(request);
+    let mut response = unwrap_batch_of_one(serde_json::from_slice(response).ok()?);
+    let id = request
+        .get("id")
+        .cloned()
+        .unwrap_or(serde_json::Value::Null);
+    response.as_object_mut()?.insert("id".to_string(), id);
+    if is_batch {
+        response = serde_json::Value::Array(vec![response]);
+    }
+    serde_json::to_vec(&response).ok()
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use arc_swap::ArcSwap;
+use axum::http::Uri;
+use hickory_resolver::TokioAsyncResolver;
+use rand::Rng;
+use tokio::time::{sleep, Duration};
+
+use crate::{config::Backend, state::Routing};
+
+/// One endpoint resolved from an SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvTarget {
+    pub host: String,
+    pub port: u16,
+    pub priority: u16,
+    pub weight: u16,
+}
+
+/// Endpoints currently resolved for backends configured with an `srv` name
+#[derive(Default)]
+pub struct SrvEndpoints {
+    targets: RwLock<HashMap<String, Vec<SrvTarget>>>,
+}
+
+impl SrvEndpoints {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// URL to send the next request for `backend` to. SRV backends get their
+    /// `url` with the host and port replaced by a resolved target, chosen by
+    /// SRV priority and weight; static backends (and SRV backends that have
+    /// not resolved yet) use `url` as is.
+    pub fn url_for(&self, backend: &Backend) -> String {
+        if backend.srv.is_none() {
+            return backend.url.clone();
+        }
+        let targets = self.targets.read().unwrap();
+        match targets.get(&backend.label).and_then(|t| pick_target(t)) {
+            Some(target) => with_authority(&backend.url, target).unwrap_or(backend.url.clone()),
+            None => backend.url.clone(),
+        }
+    }
+
+    pub fn targets(&self, label: &str) -> Vec<SrvTarget> {
+        self.targets
+            .read()
+            .unwrap()
+            .get(label)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Resolve every SRV backend once, keeping the previous targets for any
+    /// name that fails to resolve
+    pub async fn refresh(&self, resolver: &TokioAsyncResolver, backends: &[Arc<Backend>]) {
+        for backend in backends {
+            let Some(ref name) = backend.srv else {
+                continue;
+            };
+            let mut resolved: Vec<SrvTarget> = match resolver.srv_lookup(name.as_str()).await {
+                Ok(lookup) => lookup
+                    .iter()
+                    .map(|srv| SrvTarget {
+                        host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+                        port: srv.port(),
+                        priority: srv.priority(),
+                        weight: srv.weight(),
+                    })
+                    .collect(),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to resolve SRV record {} for backend {}: {}",
+                        name,
+                        backend.label,
+                        e
+                    );
+                    continue;
+                }
+            };
+            resolved
+                .sort_by(|a, b| (a.priority, &a.host, a.port).cmp(&(b.priority, &b.host, b.port)));
+
+            let mut targets = self.targets.write().unwrap();
+            if targets.get(&backend.label) != Some(&resolved) {
+                tracing::info!(
+                    "Backend {} resolved {} to {} targets: {}",
+                    backend.label,
+                    name,
+                    resolved.len(),
+                    resolved
+                        .iter()
+                        .map(|t| format!(
+                            "{}:{} (priority {}, weight {})",
+                            t.host, t.port, t.priority, t.weight
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                targets.insert(backend.label.clone(), resolved);
+            }
+        }
+    }
+}
+
+/// Pick a target from the lowest-priority group, weighted by SRV weight
+fn pick_target(targets: &[SrvTarget]) -> Option<&SrvTarget> {
+    let priority = targets.iter().map(|t| t.priority).min()?;
+    let group: Vec<&SrvTarget> = targets.iter().filter(|t| t.priority == priority).collect();
+
+    let total_weight: u32 = group.iter().map(|t| t.weight as u32).sum();
+    let mut rng = rand::thread_rng();
+    if total_weight == 0 {
+        return Some(group[rng.gen_range(0..group.len())]);
+    }
+    let mut random_weight = rng.gen_range(0..total_weight);
+    for target in &group {
+        if random_weight < target.weight as u32 {
+            return Some(target);
+        }
+        random_weight -= target.weight as u32;
+    }
+    group.first().copied()
+}
+
+/// Replace the host and port of `url` with the target's
+fn with_authority(url: &str, target: &SrvTarget) -> Option<String> {
+    let uri: Uri = url.parse().ok()?;
+    let mut parts = uri.into_parts();
+    parts.authority = Some(format!("{}:{}", target.host, target.port).parse().ok()?);
+    Uri::from_parts(parts).ok().map(|uri| uri.to_string())
+}
+
+/// Re-resolve SRV backends every `interval`
+pub async fn srv_refresh_loop(
+    resolver: TokioAsyncResolver,
+    routing: Arc<ArcSwap<Routing>>,
+    endpoints: Arc<SrvEndpoints>,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+        let routing = routing.load_full();
+        endpoints.refresh(&resolver, &routing.backends).await;
+    }
+}
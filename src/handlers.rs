@@ -1,221 +1,1965 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
 
 use axum::{
-    body::{to_bytes, Body},
+    body::{to_bytes, Body, Bytes},
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        ConnectInfo, Query, State,
+        ConnectInfo, Path, Query, State,
     },
-    http::{Request, StatusCode, Uri},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Request, StatusCode, Uri},
     middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{stream, SinkExt, StreamExt};
+use http_body_util::LengthLimitError;
+use hyper::body::Incoming;
+use metrics_exporter_prometheus::PrometheusHandle;
 use serde::{Deserialize, Serialize};
-use tokio::time::{timeout, Duration};
-use tokio_tungstenite::{connect_async, tungstenite::Message as TungsteniteMessage};
-use tracing::{error, info};
+use subtle::ConstantTimeEq;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{
+        client::IntoClientRequest, handshake::client::Request as WsRequest,
+        Message as TungsteniteMessage,
+    },
+    Connector,
+};
+use tracing::{debug, error, info, warn};
 
-use crate::state::AppState;
+use crate::{
+    access_log::LogRecord,
+    cache::CacheLookup,
+    client::HttpsClient,
+    config::{Backend, LogFormat, RedirectPolicy},
+    dedup::{rewrite_response_id, unwrap_batch_of_one, TransactionDedup},
+    health::{categorize_request_error, root_cause, BackendError, ErrorCategory},
+    metrics::{self, UsageStats},
+    rate_limit::RateLimited,
+    state::{AppState, BreakerState, InFlightGuard},
+};
+
+/// JSON-RPC error codes used in responses generated by the router
+const SERVER_ERROR: i64 = -32000;
+const UNAUTHORIZED_ERROR: i64 = -32001;
+const RATE_LIMITED_ERROR: i64 = -32005;
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST_ERROR: i64 = -32600;
+const METHOD_NOT_FOUND_ERROR: i64 = -32601;
+const INVALID_PARAMS_ERROR: i64 = -32602;
 
-const MAX_BODY_SIZE: usize = 10 * 1024 * 1024; // 10 MB
+const MAX_SAMPLED_BODY_LOG: usize = 4096;
 
 #[derive(Clone)]
 pub struct RpcMethod(pub String);
 
-#[derive(Clone)]
-pub struct SelectedBackend(pub String);
+#[derive(Clone)]
+pub struct SelectedBackend(pub String);
+
+/// Methods of the elements of a batch with more than one request
+#[derive(Clone)]
+pub struct BatchMethods(pub Vec<String>);
+
+/// ID of the request, from the client's `X-Request-Id` or generated
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header naming the backend a request should go to, when
+/// `allow_backend_override` is set
+const BACKEND_OVERRIDE_HEADER: &str = "x-backend";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// How often selection is retried while waiting for a healthy backend
+const BACKEND_WAIT_POLL: Duration = Duration::from_millis(50);
+
+/// What happened to a request, reported in the `X-Proxy-Diag` header
+#[derive(Default)]
+struct Diagnostics {
+    /// Cache status for cacheable methods: HIT, MISS or STALE
+    cache: Option<&'static str>,
+    attempts: usize,
+    /// Time spent waiting for upstream response headers, over all attempts
+    upstream: Duration,
+}
+
+impl Diagnostics {
+    /// Add the header to `resp`. Only the backend label is included, never
+    /// its URL or any credentials.
+    fn apply(&self, resp: &mut Response) {
+        let backend = resp
+            .extensions()
+            .get::<SelectedBackend>()
+            .map(|b| b.0.as_str())
+            .unwrap_or("-");
+        let mut value = format!("backend={}", backend);
+        if let Some(cache) = self.cache {
+            value.push_str(&format!("; cache={}", cache));
+        }
+        value.push_str(&format!(
+            "; attempts={}; upstream_ms={:.1}",
+            self.attempts,
+            self.upstream.as_secs_f64() * 1000.0
+        ));
+        if let Ok(value) = header::HeaderValue::from_str(&value) {
+            resp.headers_mut().insert("x-proxy-diag", value);
+        }
+    }
+}
+
+/// Hashed identifier of the API key that authenticated the request
+#[derive(Clone)]
+pub struct ApiKeyId(pub String);
+
+/// Request body as buffered by `extract_rpc_method`
+#[derive(Clone)]
+pub struct BufferedBody(pub Bytes);
+
+/// Request body as parsed by `extract_rpc_method`, when it is JSON
+#[derive(Clone)]
+pub struct ParsedBody(pub Arc<serde_json::Value>);
+
+/// Marks requests that are JSON-RPC notifications (no response expected)
+#[derive(Clone)]
+pub struct Notification;
+
+#[derive(Deserialize)]
+pub struct Params {
+    #[serde(rename = "api-key")]
+    pub api_key: Option<String>,
+}
+
+/// The key from an `Authorization: Bearer <key>` header, if present
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// The client's API key from any of the `key_params` query parameters or an
+/// `Authorization: Bearer` header. If several are given they must all be the
+/// same key.
+fn client_api_key<'a>(
+    key_params: &[String],
+    query: &'a HashMap<String, String>,
+    headers: &'a HeaderMap,
+) -> Result<Option<&'a str>, &'static str> {
+    let mut keys = key_params
+        .iter()
+        .filter_map(|name| query.get(name))
+        .map(String::as_str)
+        .chain(bearer_token(headers));
+    let key = keys.next();
+    if keys.any(|other| Some(other) != key) {
+        return Err("API keys given in the request do not match");
+    }
+    Ok(key)
+}
+
+/// The client's IP: the socket peer, or when the peer is a trusted proxy, the
+/// right-most `X-Forwarded-For` address that isn't itself a trusted proxy
+fn client_ip(state: &AppState, peer: SocketAddr, headers: &HeaderMap) -> IpAddr {
+    let trusted = |ip: &IpAddr| state.trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut ip = peer.ip();
+    if !trusted(&ip) {
+        return ip;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    for hop in forwarded.iter().rev() {
+        let Ok(hop_ip) = hop.parse::<IpAddr>() else {
+            break;
+        };
+        ip = hop_ip;
+        if !trusted(&ip) {
+            break;
+        }
+    }
+    ip
+}
+
+/// Hop-by-hop headers (RFC 7230 section 6.1) describe the client's connection
+/// to the proxy, so they are never forwarded
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers kept regardless of `forward_headers`, since the request can't be
+/// routed or understood upstream without them
+const ALWAYS_FORWARDED_HEADERS: [&str; 4] =
+    ["host", "content-type", "content-length", REQUEST_ID_HEADER];
+
+/// Drop client headers that mustn't reach upstream: hop-by-hop headers,
+/// including any named in `Connection`, then `strip_headers` and, when
+/// `forward_headers` is set, everything it doesn't list
+fn filter_request_headers(state: &AppState, headers: &mut HeaderMap) {
+    let connection_listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    let dropped: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| {
+            HOP_BY_HOP_HEADERS.contains(&name.as_str())
+                || connection_listed.contains(name)
+                || state.strip_headers.contains(name)
+                || (!state.forward_headers.is_empty()
+                    && !state.forward_headers.contains(name)
+                    && !ALWAYS_FORWARDED_HEADERS.contains(&name.as_str()))
+        })
+        .cloned()
+        .collect();
+    for name in dropped {
+        headers.remove(name);
+    }
+}
+
+/// Append the peer's address to `X-Forwarded-For`, keeping any existing
+/// chain, and set `X-Real-IP` to the client IP resolved through trusted proxies
+fn set_forwarded_for(state: &AppState, peer: SocketAddr, headers: &mut HeaderMap) {
+    let real_ip = client_ip(state, peer, headers);
+    let mut chain: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let peer_ip = peer.ip().to_string();
+    chain.push(&peer_ip);
+    let forwarded = chain.join(", ");
+
+    headers.insert("x-forwarded-for", forwarded.parse().unwrap());
+    headers.insert("x-real-ip", real_ip.to_string().parse().unwrap());
+}
+
+/// Tag the request with an ID: the client's `X-Request-Id` if it is usable,
+/// otherwise a new UUID. The ID is forwarded upstream and echoed back in the
+/// response.
+pub async fn assign_request_id(mut req: Request<Body>, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .filter(|id| id.bytes().all(|b| b.is_ascii_graphic()))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let header_value = HeaderValue::from_str(&request_id).unwrap();
+
+    req.headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value.clone());
+    req.extensions_mut().insert(RequestId(request_id));
+
+    let mut response = next.run(req).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header_value);
+    response
+}
+
+/// Whether reading a body failed because it exceeded the size limit
+fn is_length_limit(error: &axum::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+    while let Some(e) = source {
+        if e.is::<LengthLimitError>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Response for a request body that couldn't be read: 413 if it exceeded
+/// `limit` (`max_body_bytes`), 400 for any other read error
+fn unreadable_body_response(limit: usize, error: &axum::Error) -> Response {
+    if is_length_limit(error) {
+        info!("Request body exceeds {} bytes", limit);
+        return error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            INVALID_REQUEST_ERROR,
+            "Request body too large",
+            serde_json::Value::Null,
+        );
+    }
+    info!("Failed to read request body: {}", error);
+    error_response(
+        StatusCode::BAD_REQUEST,
+        INVALID_REQUEST_ERROR,
+        "Failed to read request body",
+        serde_json::Value::Null,
+    )
+}
+
+/// Bound the whole handling of a request by `total_timeout_secs`: reading
+/// the body, backend selection and retries, up to the upstream response
+/// headers. A slow-uploading client can't hold the request open past it.
+pub async fn enforce_total_timeout(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(secs) = state.total_timeout_secs else {
+        return next.run(req).await;
+    };
+    // `log_requests` runs inside the timeout and is dropped with it, so a
+    // timed-out request is logged here instead
+    let summary = RequestSummary::new(&req);
+    let start = Instant::now();
+    match timeout(Duration::from_secs(secs), next.run(req)).await {
+        Ok(resp) => resp,
+        Err(_) => {
+            info!("Request exceeded the total timeout of {}s", secs);
+            let resp = error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                SERVER_ERROR,
+                "Request timed out",
+                serde_json::Value::Null,
+            );
+            log_response(&state, addr, &summary, &resp, start.elapsed());
+            resp
+        }
+    }
+}
+
+pub async fn extract_rpc_method(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    // `log_requests` runs inside this middleware, so requests rejected here
+    // are logged and counted here instead
+    let summary = RequestSummary::new(&req);
+    let start = Instant::now();
+    let reject = |resp: Response| {
+        log_response(&state, addr, &summary, &resp, start.elapsed());
+        resp
+    };
+
+    // Read body, extract "method" field, then reconstruct the request
+    let (mut parts, body) = req.into_parts();
+    let mut body_bytes = match to_bytes(body, state.max_body_bytes).await {
+        Ok(bytes) => bytes,
+        Err(e) => return reject(unreadable_body_response(state.max_body_bytes, &e)),
+    };
+
+    let parsed = serde_json::from_slice::<serde_json::Value>(&body_bytes);
+    if state.strict_jsonrpc
+        && parts.method == Method::POST
+        && !parts.uri.path().starts_with("/admin/")
+    {
+        if let Some(resp) = reject_invalid_envelope(parsed.as_ref()) {
+            return reject(resp);
+        }
+    }
+    if state.compact_request_body && parsed.is_ok() {
+        let compacted = compact_json(&body_bytes);
+        if compacted.len() < body_bytes.len() {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, compacted.len().into());
+            body_bytes = Bytes::from(compacted);
+        }
+    }
+
+    let mut req = Request::from_parts(parts, Body::from(body_bytes.clone()));
+    req.extensions_mut()
+        .insert(BufferedBody(body_bytes.clone()));
+
+    // Try to extract "method" from JSON; a batch of one counts as its element
+    if let Ok(json) = parsed {
+        let single = match &json {
+            serde_json::Value::Array(items) if items.len() == 1 => &items[0],
+            json => json,
+        };
+        if let Some(method) = single.get("method").and_then(|m| m.as_str()) {
+            req.extensions_mut().insert(RpcMethod(method.to_string()));
+        }
+        if let Some(items) = json.as_array().filter(|items| items.len() > 1) {
+            let methods = items
+                .iter()
+                .filter_map(|item| item.get("method").and_then(|m| m.as_str()))
+                .map(str::to_string)
+                .collect();
+            req.extensions_mut().insert(BatchMethods(methods));
+        }
+        if is_notification(&json) {
+            req.extensions_mut().insert(Notification);
+        }
+        req.extensions_mut().insert(ParsedBody(Arc::new(json)));
+    }
+
+    next.run(req).await
+}
+
+/// With `strict_jsonrpc`, reject a body that isn't a well-formed JSON-RPC 2.0
+/// request or batch before any backend is contacted
+fn reject_invalid_envelope(
+    parsed: Result<&serde_json::Value, &serde_json::Error>,
+) -> Option<Response> {
+    let json = match parsed {
+        Ok(json) => json,
+        Err(e) => {
+            info!("Rejecting request that is not valid JSON: {}", e);
+            return Some(error_response(
+                StatusCode::BAD_REQUEST,
+                PARSE_ERROR,
+                "Parse error",
+                serde_json::Value::Null,
+            ));
+        }
+    };
+    let problem = match json {
+        serde_json::Value::Array(items) if items.is_empty() => Some("empty batch".to_string()),
+        serde_json::Value::Array(items) => items.iter().enumerate().find_map(|(index, item)| {
+            envelope_error(item).map(|error| format!("batch element {}: {}", index, error))
+        }),
+        single => envelope_error(single).map(str::to_string),
+    };
+    problem.map(|problem| {
+        info!("Rejecting invalid JSON-RPC request: {}", problem);
+        let id = json
+            .get("id")
+            .filter(|id| id.is_string() || id.is_number())
+            .cloned()
+            .unwrap_or(serde_json::Value::Null);
+        error_response(
+            StatusCode::BAD_REQUEST,
+            INVALID_REQUEST_ERROR,
+            &format!("Invalid request: {}", problem),
+            id,
+        )
+    })
+}
+
+/// What is wrong with a single JSON-RPC request object, if anything. A
+/// missing `id` is allowed, since that makes the request a notification.
+fn envelope_error(request: &serde_json::Value) -> Option<&'static str> {
+    let Some(request) = request.as_object() else {
+        return Some("request must be an object");
+    };
+    if request.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0") {
+        return Some("\"jsonrpc\" must be \"2.0\"");
+    }
+    if !request.get("method").is_some_and(|m| m.is_string()) {
+        return Some("\"method\" must be a string");
+    }
+    if request
+        .get("params")
+        .is_some_and(|p| !p.is_array() && !p.is_object())
+    {
+        return Some("\"params\" must be an array or object");
+    }
+    if request
+        .get("id")
+        .is_some_and(|id| !id.is_string() && !id.is_number() && !id.is_null())
+    {
+        return Some("\"id\" must be a string, number or null");
+    }
+    None
+}
+
+/// A JSON-RPC notification is a request without an `id`. A batch counts as a
+/// notification only if every element is one, since otherwise a response is
+/// still expected.
+fn is_notification(json: &serde_json::Value) -> bool {
+    let is_single = |v: &serde_json::Value| {
+        v.as_object()
+            .is_some_and(|obj| obj.contains_key("method") && !obj.contains_key("id"))
+    };
+    match json {
+        serde_json::Value::Array(items) => !items.is_empty() && items.iter().all(is_single),
+        single => is_single(single),
+    }
+}
+
+/// The parts of a request that are logged, captured before it is consumed
+struct RequestSummary {
+    method: Method,
+    path: String,
+    rpc_method: Option<RpcMethod>,
+    request_id: Option<RequestId>,
+}
+
+impl RequestSummary {
+    fn new(req: &Request<Body>) -> Self {
+        Self {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            rpc_method: req.extensions().get::<RpcMethod>().cloned(),
+            request_id: req.extensions().get::<RequestId>().cloned(),
+        }
+    }
+}
+
+pub async fn log_requests(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let summary = RequestSummary::new(&req);
+    let start = std::time::Instant::now();
+    let response = next.run(req).await;
+    log_response(&state, addr, &summary, &response, start.elapsed());
+    response
+}
+
+/// Record a finished request in the request metrics and recent requests,
+/// and write its log line
+fn log_response(
+    state: &AppState,
+    addr: SocketAddr,
+    request: &RequestSummary,
+    response: &Response,
+    duration: Duration,
+) {
+    let method = &request.method;
+    let path = &request.path;
+
+    // Extract backend from response extensions (set by proxy handler)
+    let backend = response.extensions().get::<SelectedBackend>().cloned();
+    let key_id = response.extensions().get::<ApiKeyId>().cloned();
+    // Locally built bodies know their size before hyper adds Content-Length;
+    // streamed responses without one are left unknown
+    let response_bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .or_else(|| hyper::body::Body::size_hint(response.body()).exact());
+    metrics::record_request(response.status().as_u16(), duration);
+
+    let record = LogRecord {
+        method,
+        path,
+        remote_addr: addr,
+        status: response.status(),
+        response_bytes,
+        duration,
+        rpc_method: request.rpc_method.as_ref().map(|m| m.0.as_str()),
+        backend: backend.as_ref().map(|b| b.0.as_str()),
+        key_id: key_id.as_ref().map(|k| k.0.as_str()),
+        request_id: request.request_id.as_ref().map(|id| id.0.as_str()),
+    };
+    state.recent_requests.push(&record);
+
+    if state.log_format == LogFormat::Json {
+        info!(
+            method = %method,
+            path = %path,
+            remote_addr = %addr,
+            status = response.status().as_u16(),
+            response_bytes,
+            duration_ms = duration.as_secs_f64() * 1000.0,
+            rpc_method = record.rpc_method,
+            backend = record.backend,
+            key_id = record.key_id,
+            request_id = record.request_id,
+            "request"
+        );
+        return;
+    }
+
+    if let Some(ref template) = state.log_template {
+        info!("{}", template.render(&record));
+        return;
+    }
+
+    let mut line = format!(
+        "{} {} {} {} {:?}",
+        method,
+        path,
+        addr,
+        response.status().as_u16(),
+        duration
+    );
+    if let Some(bytes) = response_bytes {
+        line.push_str(&format!(" response_bytes={}", bytes));
+    }
+    if let Some(m) = record.rpc_method {
+        line.push_str(&format!(" rpc_method={}", m));
+    }
+    if let Some(b) = record.backend {
+        line.push_str(&format!(" backend={}", b));
+    }
+    if let Some(k) = record.key_id {
+        line.push_str(&format!(" key_id={}", k));
+    }
+    if let Some(id) = record.request_id {
+        line.push_str(&format!(" request_id={}", id));
+    }
+    info!("{}", line);
+}
+
+pub async fn proxy(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+) -> impl IntoResponse {
+    if let Some(ref limiter) = state.ip_rate_limiter {
+        let ip = client_ip(&state, addr, req.headers());
+        if let Err(limited) = limiter.check(ip) {
+            info!("Rate limit exceeded for client IP {}", ip);
+            return rate_limited_response(&state.rate_limit_message, &req, limited);
+        }
+    }
+
+    let unauthorized = |req: &Request<Body>| {
+        error_response(
+            StatusCode::UNAUTHORIZED,
+            UNAUTHORIZED_ERROR,
+            "Unauthorized",
+            buffered_request_id(req),
+        )
+    };
+    let api_keys = state.routing.load().api_keys.clone();
+    let candidate =
+        client_api_key(&state.key_params, &query, req.headers()).map(|key| key.map(str::to_string));
+    let key_id = match candidate {
+        Ok(Some(key)) => match api_keys.verify_request(&key).await {
+            Some(key_id) => key_id,
+            None => {
+                info!("API key '{}' is invalid", key);
+                return unauthorized(&req);
+            }
+        },
+        Ok(None) => {
+            info!("No API key provided");
+            return unauthorized(&req);
+        }
+        Err(reason) => {
+            info!("{}", reason);
+            return unauthorized(&req);
+        }
+    };
+    // The client's bearer token is for the proxy, never for upstreams
+    if bearer_token(req.headers()).is_some() {
+        req.headers_mut().remove(header::AUTHORIZATION);
+    }
+    filter_request_headers(&state, req.headers_mut());
+    if state.forward_client_ip {
+        set_forwarded_for(&state, addr, req.headers_mut());
+    }
+
+    let mut diag = Diagnostics::default();
+    let started = Instant::now();
+    let rpc_method = req.extensions().get::<RpcMethod>().map(|m| m.0.clone());
+    let mut resp = if let Some(method) = disallowed_method(&state, &req) {
+        info!("Rejected request for blocked method {}", method);
+        error_response(
+            StatusCode::FORBIDDEN,
+            METHOD_NOT_FOUND_ERROR,
+            &format!("Method not found: {}", method),
+            buffered_request_id(&req),
+        )
+    } else if let Err(limited) = state.key_rate_limiter.check(&key_id) {
+        info!("Rate limit exceeded for key {}", key_id);
+        rate_limited_response(&state.rate_limit_message, &req, limited)
+    } else if let Some((method, limited)) = method_rate_limited(&state, &req, &key_id) {
+        info!("Method {} rate limit exceeded for key {}", method, key_id);
+        rate_limited_response(&state.rate_limit_message, &req, limited)
+    } else {
+        forward(&state, addr, &key_id, req, &mut diag).await
+    };
+    if state.diag_header {
+        diag.apply(&mut resp);
+    }
+
+    let is_error = resp.status().is_client_error() || resp.status().is_server_error();
+    state.api_key_metrics.record(&key_id, is_error);
+    if let Some(ref method) = rpc_method {
+        state
+            .method_metrics
+            .record(method, !is_error, started.elapsed());
+    }
+    resp.extensions_mut().insert(ApiKeyId(key_id));
+    resp
+}
+
+/// The first method of the request (or of any request in a batch) that
+/// `allowed_methods` / `blocked_methods` rejects
+fn disallowed_method(state: &AppState, req: &Request<Body>) -> Option<String> {
+    let single = req.extensions().get::<RpcMethod>().map(|m| &m.0);
+    let batch = req.extensions().get::<BatchMethods>().into_iter();
+    single
+        .into_iter()
+        .chain(batch.flat_map(|b| &b.0))
+        .find(|method| !state.method_allowed(method))
+        .cloned()
+}
+
+/// The first method of the request (or of any request in a batch) that is
+/// over its `method_rate_limits` entry. Each batch element takes a token, so
+/// batching doesn't get around the limit.
+fn method_rate_limited(
+    state: &AppState,
+    req: &Request<Body>,
+    key_id: &str,
+) -> Option<(String, RateLimited)> {
+    let single = req.extensions().get::<RpcMethod>().map(|m| &m.0);
+    let batch = req.extensions().get::<BatchMethods>().into_iter();
+    single
+        .into_iter()
+        .chain(batch.flat_map(|b| &b.0))
+        .find_map(|method| {
+            let limited = state.method_rate_limiter.check(method, key_id).err()?;
+            Some((method.clone(), limited))
+        })
+}
+
+/// Forward an authenticated request to a backend, retrying on other
+/// backends where allowed
+async fn forward(
+    state: &AppState,
+    addr: SocketAddr,
+    key_id: &str,
+    req: Request<Body>,
+    diag: &mut Diagnostics,
+) -> Response {
+    // Get RPC method from extension (set by extract_rpc_method middleware)
+    let rpc_method = req.extensions().get::<RpcMethod>().map(|m| m.0.clone());
+    // A multi-request batch is routed by its routed methods, if they agree
+    let route_method = rpc_method.clone().or_else(|| {
+        req.extensions()
+            .get::<BatchMethods>()
+            .and_then(|batch| state.routing.load().batch_route_method(&batch.0))
+    });
+    let is_notification = req.extensions().get::<Notification>().is_some();
+    let allowed_backends = state.routing.load().key_backends.get(key_id).cloned();
+    let validate_result = rpc_method
+        .as_deref()
+        .is_some_and(|method| state.require_result_methods.iter().any(|m| m == method));
+
+    // Keep the buffered body so the request can be replayed on another backend
+    let (parts, body) = req.into_parts();
+    let request_body = match parts.extensions.get::<BufferedBody>() {
+        Some(buffered) => buffered.0.clone(),
+        None => match to_bytes(body, state.max_body_bytes).await {
+            Ok(bytes) => bytes,
+            Err(e) => return unreadable_body_response(state.max_body_bytes, &e),
+        },
+    };
+    let id = jsonrpc_request_id(&request_body);
+    let hash_key = rpc_method
+        .as_deref()
+        .zip(parts.extensions.get::<ParsedBody>())
+        .and_then(|(method, parsed)| state.hash_key(method, &parsed.0));
+
+    if let (Some("rpc.discover"), Some(methods)) =
+        (rpc_method.as_deref(), &state.rpc_discover_methods)
+    {
+        return rpc_discover_response(methods, &request_body);
+    }
+
+    // Answer duplicate transaction submissions from the dedup window
+    let dedup_key = match state.transaction_dedup {
+        Some(_) if rpc_method.as_deref() == Some("sendTransaction") => {
+            TransactionDedup::key(&request_body)
+        }
+        _ => None,
+    };
+    if let (Some(dedup), Some(key)) = (&state.transaction_dedup, &dedup_key) {
+        if let Some(cached) = dedup.get(key) {
+            info!("Duplicate sendTransaction answered from dedup window");
+            let body = rewrite_response_id(&cached, &request_body)
+                .map(Bytes::from)
+                .unwrap_or(cached);
+            return ([(header::CONTENT_TYPE, "application/json")], body).into_response();
+        }
+    }
+
+    // Serve cacheable methods from the response cache
+    let cache_key = rpc_method
+        .as_deref()
+        .filter(|_| state.response_cache.is_enabled())
+        .and_then(|method| state.response_cache.key(method, &request_body));
+    if let Some(ref key) = cache_key {
+        let lookup = state
+            .response_cache
+            .lookup(key, state.health_state.latest_slot());
+        diag.cache = Some(lookup.status());
+        if let CacheLookup::Hit(cached) = lookup {
+            info!(
+                "Cache hit for {}",
+                rpc_method.as_deref().unwrap_or_default()
+            );
+            let body = rewrite_response_id(&cached, &request_body)
+                .map(Bytes::from)
+                .unwrap_or(cached);
+            return ([(header::CONTENT_TYPE, "application/json")], body).into_response();
+        }
+    }
+
+    // Rebuild URI (remove ?api-key=... from request)
+    let request_path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|x| x.as_str())
+        .unwrap_or("/");
+
+    // Remove api-key from the incoming request's query parameters
+    let cleaned_request_path = strip_query_params(request_path_and_query, &state.key_params);
+
+    // A client-chosen backend, for reproducing a single provider's behavior
+    let backend_override = parts
+        .headers
+        .get(BACKEND_OVERRIDE_HEADER)
+        .filter(|_| state.allow_backend_override)
+        .and_then(|value| value.to_str().ok());
+
+    let mut attempted: Vec<String> = Vec::new();
+    // The response of the last failed attempt and why it failed, returned
+    // (and dead-lettered) once retries run out of backends
+    let mut last_failure: Option<(Response, String)> = None;
+    // With `wait_for_backend_ms`, set when selection first finds no backend
+    let mut wait_deadline: Option<Instant> = None;
+
+    loop {
+        // The override only applies to the first attempt; retries and
+        // unavailable overrides go through normal selection
+        let selection = backend_override
+            .filter(|_| attempted.is_empty())
+            .and_then(|label| state.select_override(label, allowed_backends.as_ref()))
+            .filter(|selection| state.acquire_backend_capacity(&selection.0));
+        // Select backend based on method routing or weighted random. Another
+        // request may take the last token between selection and acquisition,
+        // so a backend that loses that race is excluded and selection re-run.
+        let selection = selection.or_else(|| {
+            let mut excluded = attempted.clone();
+            loop {
+                let selection = state.select_backend_for_connection(
+                    addr,
+                    route_method.as_deref(),
+                    hash_key.as_deref(),
+                    &excluded,
+                    allowed_backends.as_ref(),
+                )?;
+                if state.acquire_backend_capacity(&selection.0) {
+                    return Some(selection);
+                }
+                excluded.push(selection.0);
+            }
+        });
+        let (backend_label, backend_url) = match selection {
+            Some(selection) => selection,
+            None => {
+                // Retries exhausted every candidate; return the last failure
+                if let Some((resp, reason)) = last_failure {
+                    record_dead_letter(
+                        state,
+                        rpc_method.as_deref(),
+                        &request_body,
+                        attempted.last().map(String::as_str),
+                        &reason,
+                    );
+                    return resp;
+                }
+                if let Some(retry_after) = state.rate_limited_retry_after() {
+                    info!("All healthy backends are at their max_rps");
+                    record_dead_letter(
+                        state,
+                        rpc_method.as_deref(),
+                        &request_body,
+                        None,
+                        "all backends rate limited",
+                    );
+                    return rate_limited_backends_response(retry_after, id);
+                }
+                // A health check may bring a backend back within a short blip
+                if let Some(wait) = state.wait_for_backend.filter(|_| attempted.is_empty()) {
+                    let deadline = *wait_deadline.get_or_insert_with(|| Instant::now() + wait);
+                    if Instant::now() < deadline {
+                        tokio::time::sleep(BACKEND_WAIT_POLL.min(deadline - Instant::now())).await;
+                        continue;
+                    }
+                    info!(
+                        "No healthy backend became available within {}ms",
+                        wait.as_millis()
+                    );
+                }
+                tracing::error!("No healthy backends available for request");
+                record_dead_letter(
+                    state,
+                    rpc_method.as_deref(),
+                    &request_body,
+                    None,
+                    "no healthy backends available",
+                );
+                return error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    SERVER_ERROR,
+                    "No healthy backends available",
+                    id,
+                );
+            }
+        };
+        let backend_label = backend_label.as_str();
+        let backend_url = state.endpoint_url(backend_label, &backend_url);
+        attempted.push(backend_label.to_string());
+        diag.attempts = attempted.len();
+        // Released when this attempt ends, or once a streamed body completes
+        let in_flight = state.in_flight.track(backend_label);
+        let retries_left = state.failover_allowed(attempted.len());
+
+        // Remove params this backend doesn't support for the method
+        let backend = state.backend(backend_label);
+        let mut headers = parts.headers.clone();
+        headers.remove(BACKEND_OVERRIDE_HEADER);
+        let body_bytes = match rpc_method.as_deref().and_then(|method| {
+            backend
+                .as_ref()
+                .and_then(|b| b.strip_params.get(method))
+                .and_then(|keys| strip_request_params(&request_body, keys))
+        }) {
+            Some(stripped) => {
+                info!(
+                    "Stripped unsupported params from {} request for backend {}",
+                    rpc_method.as_deref().unwrap_or_default(),
+                    backend_label
+                );
+                headers.insert(header::CONTENT_LENGTH, stripped.len().into());
+                Bytes::from(stripped)
+            }
+            None => request_body.clone(),
+        };
+
+        // Build URI with selected backend
+        let uri_string = if cleaned_request_path == "/" {
+            // For root path requests, don't add trailing slash
+            backend_url.trim_end_matches('/').to_string()
+        } else if backend_url.ends_with('/') && cleaned_request_path.starts_with('/') {
+            // Avoid double slashes
+            format!("{}{}", backend_url, &cleaned_request_path[1..])
+        } else {
+            format!("{}{}", backend_url, cleaned_request_path)
+        };
+        // Provider credentials are added only after the client's api-key has
+        // been removed from the path
+        let uri_string = match backend {
+            Some(ref backend) => backend.authenticated_url(&uri_string),
+            None => uri_string,
+        };
+        let Ok(parsed_uri) = uri_string.parse::<Uri>() else {
+            error!(
+                "Backend {} produced an invalid upstream URI for path {}",
+                backend_label, cleaned_request_path
+            );
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                SERVER_ERROR,
+                "Invalid backend URL",
+                id,
+            );
+        };
+        if let Some(ref backend) = backend {
+            backend.insert_headers(&mut headers);
+        }
+
+        let preserve_host = backend.as_ref().is_some_and(|b| b.preserve_host);
+        if let Err(host_value) = set_upstream_host(&mut headers, &parsed_uri, preserve_host) {
+            error!(
+                "Backend {} has an invalid host {}",
+                backend_label, host_value
+            );
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                SERVER_ERROR,
+                "Invalid backend URL",
+                id,
+            );
+        }
+
+        let sampled = state.sample_body(backend_label);
+        if sampled {
+            debug!(
+                target: "body_sample",
+                "Sampled request to backend {}: {}",
+                backend_label,
+                truncate_for_log(&body_bytes)
+            );
+        }
+
+        // Forward request
+        let upstream_timeout = state.upstream_timeout();
+        let client = state.clients.get(backend_label);
+        let upstream_start = Instant::now();
+        let result = timeout(
+            upstream_timeout,
+            send_upstream(
+                &client,
+                &parts,
+                parsed_uri,
+                headers,
+                body_bytes,
+                backend.as_deref(),
+                state.redirects_to_follow(rpc_method.as_deref()),
+            ),
+        )
+        .await;
+        diag.upstream += upstream_start.elapsed();
+        if let Ok(Ok(_)) = result {
+            state
+                .latency
+                .record(backend_label, upstream_start.elapsed());
+            state
+                .backend_metrics
+                .record_latency(backend_label, upstream_start.elapsed());
+        }
+
+        match result {
+            Ok(Ok(resp)) if state.retry_status_codes.contains(&resp.status().as_u16()) => {
+                let status = resp.status();
+                state.record_attempt(backend_label, Err(BackendError::status(status.as_u16())));
+                let resp = finish_response(state, resp.map(Body::new), backend_label);
+                let reason = format!("upstream returned {}", status);
+                if retries_left {
+                    info!(
+                        "Retrying request (attempt {}) after status {} from backend {}",
+                        attempted.len() + 1,
+                        status,
+                        backend_label
+                    );
+                    last_failure = Some((resp, reason));
+                    continue;
+                }
+                record_dead_letter(
+                    state,
+                    rpc_method.as_deref(),
+                    &request_body,
+                    Some(backend_label),
+                    &reason,
+                );
+                return resp;
+            }
+            Ok(Ok(resp))
+                if resp.status().is_redirection()
+                    && state.redirect_policy != RedirectPolicy::Forward =>
+            {
+                // The client can't follow a redirect to an internal backend URL
+                state.record_attempt(
+                    backend_label,
+                    Err(BackendError::InvalidResponse(format!(
+                        "Unexpected redirect {}",
+                        resp.status()
+                    ))),
+                );
+                warn!(
+                    "Backend {} answered with unexpected redirect {}",
+                    backend_label,
+                    resp.status()
+                );
+                let mut failure = error_response(
+                    StatusCode::BAD_GATEWAY,
+                    SERVER_ERROR,
+                    &format!(
+                        "Backend returned an unexpected redirect ({})",
+                        resp.status()
+                    ),
+                    id.clone(),
+                );
+                failure
+                    .extensions_mut()
+                    .insert(SelectedBackend(backend_label.to_string()));
+                if retries_left {
+                    last_failure = Some((failure, "unexpected redirect".to_string()));
+                    continue;
+                }
+                record_dead_letter(
+                    state,
+                    rpc_method.as_deref(),
+                    &request_body,
+                    Some(backend_label),
+                    "unexpected redirect",
+                );
+                return failure;
+            }
+            Ok(Ok(resp))
+                if is_notification
+                    && state.notification_no_content
+                    && resp.status().is_success() =>
+            {
+                // Notifications expect no response; drop whatever the backend sent
+                state.record_attempt(backend_label, Ok(()));
+                let mut resp = StatusCode::NO_CONTENT.into_response();
+                resp.extensions_mut()
+                    .insert(SelectedBackend(backend_label.to_string()));
+                return resp;
+            }
+            Ok(Ok(resp))
+                if sampled || validate_result || dedup_key.is_some() || cache_key.is_some() =>
+            {
+                let (resp_parts, resp_body) = resp.into_parts();
+                // The body read shares the attempt's timeout with the headers
+                let read = timeout(
+                    upstream_timeout.saturating_sub(upstream_start.elapsed()),
+                    to_bytes(Body::new(resp_body), state.max_response_bytes),
+                )
+                .await;
+                let resp_bytes = match read {
+                    Ok(Ok(bytes)) => bytes,
+                    Err(_) => {
+                        info!("Timed out reading response from backend {}", backend_label);
+                        state.record_attempt(
+                            backend_label,
+                            Err(BackendError::Timeout(upstream_timeout)),
+                        );
+                        let message = format!(
+                            "Upstream request timed out after {:.1}s",
+                            upstream_timeout.as_secs_f64()
+                        );
+                        let failure = error_response(
+                            StatusCode::GATEWAY_TIMEOUT,
+                            SERVER_ERROR,
+                            &message,
+                            id.clone(),
+                        );
+                        if retries_left {
+                            info!(
+                                "Retrying request (attempt {}) after timeout from backend {}",
+                                attempted.len() + 1,
+                                backend_label
+                            );
+                            last_failure = Some((failure, message));
+                            continue;
+                        }
+                        record_dead_letter(
+                            state,
+                            rpc_method.as_deref(),
+                            &request_body,
+                            Some(backend_label),
+                            &message,
+                        );
+                        return failure;
+                    }
+                    Ok(Err(e)) => {
+                        info!(
+                            "Failed to read response from backend {}: {}",
+                            backend_label, e
+                        );
+                        state.record_attempt(
+                            backend_label,
+                            Err(BackendError::BodyRead(e.to_string())),
+                        );
+                        let failure = error_response(
+                            StatusCode::BAD_GATEWAY,
+                            SERVER_ERROR,
+                            &format!("Proxy error: {}", e),
+                            id.clone(),
+                        );
+                        let reason = format!("failed to read response: {}", e);
+                        if retries_left {
+                            info!(
+                                "Retrying request (attempt {}) after error from backend {}",
+                                attempted.len() + 1,
+                                backend_label
+                            );
+                            last_failure = Some((failure, reason));
+                            continue;
+                        }
+                        record_dead_letter(
+                            state,
+                            rpc_method.as_deref(),
+                            &request_body,
+                            Some(backend_label),
+                            &reason,
+                        );
+                        return failure;
+                    }
+                };
+                let status = resp_parts.status;
+                let resp = Response::from_parts(resp_parts, Body::from(resp_bytes.clone()));
+
+                if sampled {
+                    debug!(
+                        target: "body_sample",
+                        "Sampled response from backend {} ({}): {}",
+                        backend_label,
+                        status,
+                        truncate_for_log(&resp_bytes)
+                    );
+                }
+
+                let valid =
+                    !(validate_result && status == StatusCode::OK && !has_result(&resp_bytes));
+                if valid {
+                    state.record_attempt(backend_label, Ok(()));
+                } else {
+                    let error = format!(
+                        "{} returned a null or missing result",
+                        rpc_method.as_deref().unwrap_or_default()
+                    );
+                    warn!("Backend {} {}", backend_label, error);
+                    state.record_attempt(backend_label, Err(BackendError::InvalidResponse(error)));
+                }
+
+                if status == StatusCode::OK && has_non_null_result(&resp_bytes) {
+                    if let (Some(dedup), Some(key)) = (&state.transaction_dedup, &dedup_key) {
+                        dedup.insert(key.clone(), resp_bytes.clone());
+                    }
+                    if let (Some(method), Some(key)) = (rpc_method.as_deref(), &cache_key) {
+                        state.response_cache.insert(
+                            method,
+                            key.clone(),
+                            resp_bytes.clone(),
+                            state.health_state.latest_slot(),
+                        );
+                    }
+                }
+                if !valid && retries_left {
+                    info!(
+                        "Retrying request (attempt {}) after empty result from backend {}",
+                        attempted.len() + 1,
+                        backend_label
+                    );
+                    last_failure = Some((
+                        finish_response(state, resp, backend_label),
+                        "null or missing result".to_string(),
+                    ));
+                    continue;
+                }
+                return finish_response(state, resp, backend_label);
+            }
+            Ok(Ok(resp)) => {
+                state.record_attempt(backend_label, Ok(()));
+                return finish_response(state, resp.map(Body::new), backend_label)
+                    .map(|body| release_on_complete(body, in_flight));
+            }
+            Ok(Err(err)) => {
+                state.record_attempt(backend_label, Err(BackendError::connect(&err)));
+                info!("Backend request failed: {} (error type: {:?})", err, err);
+                if retries_left {
+                    info!(
+                        "Retrying request (attempt {}) after error from backend {}",
+                        attempted.len() + 1,
+                        backend_label
+                    );
+                    last_failure = Some((
+                        error_response(
+                            StatusCode::BAD_GATEWAY,
+                            SERVER_ERROR,
+                            &proxy_error_message(backend_label, &err),
+                            id.clone(),
+                        ),
+                        format!("proxy error: {}", err),
+                    ));
+                    continue;
+                }
+                record_dead_letter(
+                    state,
+                    rpc_method.as_deref(),
+                    &request_body,
+                    Some(backend_label),
+                    &format!("proxy error: {}", err),
+                );
+                return error_response(
+                    StatusCode::BAD_GATEWAY,
+                    SERVER_ERROR,
+                    &proxy_error_message(backend_label, &err),
+                    id,
+                );
+            }
+            Err(_) => {
+                state.record_attempt(backend_label, Err(BackendError::Timeout(upstream_timeout)));
+                let message = format!(
+                    "Upstream request timed out after {:.1}s",
+                    upstream_timeout.as_secs_f64()
+                );
+                if retries_left {
+                    info!(
+                        "Retrying request (attempt {}) after timeout from backend {}",
+                        attempted.len() + 1,
+                        backend_label
+                    );
+                    last_failure = Some((
+                        error_response(
+                            StatusCode::GATEWAY_TIMEOUT,
+                            SERVER_ERROR,
+                            &message,
+                            id.clone(),
+                        ),
+                        message,
+                    ));
+                    continue;
+                }
+                record_dead_letter(
+                    state,
+                    rpc_method.as_deref(),
+                    &request_body,
+                    Some(backend_label),
+                    &message,
+                );
+                return error_response(StatusCode::GATEWAY_TIMEOUT, SERVER_ERROR, &message, id);
+            }
+        }
+    }
+}
+
+/// Client-facing message for a failed upstream request. hyper's own message
+/// hides the cause, so TLS failures (such as a rejected client certificate)
+/// name the backend and the underlying error instead.
+fn proxy_error_message(backend_label: &str, err: &hyper_util::client::legacy::Error) -> String {
+    if categorize_request_error(err) != ErrorCategory::Tls {
+        return format!("Proxy error: {}", err);
+    }
+    format!(
+        "TLS handshake with backend {} failed: {}",
+        backend_label,
+        root_cause(err)
+    )
+}
+
+/// Apply the response stream limit and tag the response with the backend
+/// that served it (read by `log_requests`)
+fn finish_response(state: &AppState, resp: Response<Body>, backend_label: &str) -> Response {
+    let mut resp = match state.response_stream_timeout_secs {
+        Some(secs) => resp.map(|body| {
+            limit_body_duration(body, Duration::from_secs(secs), backend_label.to_string())
+        }),
+        None => resp,
+    };
+    resp.extensions_mut()
+        .insert(SelectedBackend(backend_label.to_string()));
+    resp
+}
+
+/// Keep `guard` alive until the body has been fully streamed (or dropped)
+fn release_on_complete(body: Body, guard: Option<InFlightGuard>) -> Body {
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        let _guard = &guard;
+        chunk
+    }))
+}
+
+/// Render a body for a log line, truncated to `MAX_SAMPLED_BODY_LOG` bytes
+fn truncate_for_log(body: &[u8]) -> String {
+    if body.len() <= MAX_SAMPLED_BODY_LOG {
+        String::from_utf8_lossy(body).into_owned()
+    } else {
+        format!(
+            "{}... ({} bytes total)",
+            String::from_utf8_lossy(&body[..MAX_SAMPLED_BODY_LOG]),
+            body.len()
+        )
+    }
+}
+
+/// Remove whitespace outside of strings from a valid JSON document. Unlike
+/// re-serializing the parsed value this keeps key order and number formatting
+/// byte-for-byte.
+fn compact_json(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            out.push(byte);
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+            }
+        } else if !matches!(byte, b' ' | b'\n' | b'\r' | b'\t') {
+            if byte == b'"' {
+                in_string = true;
+            }
+            out.push(byte);
+        }
+    }
+    out
+}
+
+/// Whether a single JSON-RPC response succeeded with a non-null `result`
+fn has_non_null_result(body: &[u8]) -> bool {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .map(unwrap_batch_of_one)
+        .and_then(|json| json.get("result").map(|r| !r.is_null()))
+        .unwrap_or(false)
+}
+
+/// Whether a JSON-RPC response carries a non-null `result` (or an explicit
+/// `error`). For batches every element must.
+fn has_result(body: &[u8]) -> bool {
+    let is_complete = |v: &serde_json::Value| {
+        v.get("error").is_some() || v.get("result").is_some_and(|r| !r.is_null())
+    };
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Array(items)) => items.iter().all(is_complete),
+        Ok(single) => is_complete(&single),
+        Err(_) => false,
+    }
+}
+
+/// Write an undeliverable request to the dead-letter log if one is configured
+/// and the method is one whose loss must never go unnoticed
+fn record_dead_letter(
+    state: &AppState,
+    rpc_method: Option<&str>,
+    body: &Bytes,
+    backend: Option<&str>,
+    reason: &str,
+) {
+    let (Some(log), Some(method)) = (&state.dead_letter, rpc_method) else {
+        return;
+    };
+    if state.dead_letter_methods.iter().any(|m| m == method) {
+        log.record(method, backend, reason, body);
+    }
+}
+
+/// Remove `keys` from the JSON-RPC params (either a params object or any
+/// config object inside a positional params array). Returns the re-serialized
+/// body only if something was removed.
+fn strip_request_params(body: &[u8], keys: &[String]) -> Option<Vec<u8>> {
+    let mut json = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let mut stripped = false;
+
+    let mut strip_object = |obj: &mut serde_json::Map<String, serde_json::Value>| {
+        for key in keys {
+            stripped |= obj.remove(key).is_some();
+        }
+    };
+
+    match json.get_mut("params")? {
+        serde_json::Value::Array(items) => {
+            for item in items {
+                if let serde_json::Value::Object(obj) = item {
+                    strip_object(obj);
+                }
+            }
+        }
+        serde_json::Value::Object(obj) => strip_object(obj),
+        _ => {}
+    }
+
+    if !stripped {
+        return None;
+    }
+    serde_json::to_vec(&json).ok()
+}
+
+/// Answer `rpc.discover` with an OpenRPC document listing `methods`
+fn rpc_discover_response(methods: &[String], request_body: &Bytes) -> Response {
+    let id = serde_json::from_slice::<serde_json::Value>(request_body)
+        .ok()
+        .and_then(|json| json.get("id").cloned())
+        .unwrap_or(serde_json::Value::Null);
+    let methods: Vec<serde_json::Value> = methods
+        .iter()
+        .map(|name| serde_json::json!({ "name": name, "params": [] }))
+        .collect();
+
+    Json(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": {
+            "openrpc": "1.2.6",
+            "info": {
+                "title": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "methods": methods,
+        },
+    }))
+    .into_response()
+}
+
+/// Send a request upstream, following up to `max_redirects` redirects with
+/// the same method, headers and body
+async fn send_upstream(
+    client: &HttpsClient,
+    parts: &axum::http::request::Parts,
+    mut uri: Uri,
+    mut headers: HeaderMap,
+    body: Bytes,
+    backend: Option<&Backend>,
+    max_redirects: u32,
+) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+    let mut redirects = 0;
+    loop {
+        let mut upstream_req = Request::new(Body::from(body.clone()));
+        *upstream_req.method_mut() = parts.method.clone();
+        *upstream_req.uri_mut() = uri.clone();
+        *upstream_req.headers_mut() = headers.clone();
+
+        let resp = client.request(upstream_req).await?;
+        if !resp.status().is_redirection() || redirects >= max_redirects {
+            return Ok(resp);
+        }
+        let Some(next) = resp
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| resolve_redirect(&uri, location))
+        else {
+            return Ok(resp);
+        };
+
+        redirects += 1;
+        info!(
+            "Following redirect {} -> {}",
+            without_query(&uri),
+            without_query(&next)
+        );
+        if next.authority() != uri.authority() {
+            // Provider credentials stay with the provider's host
+            headers.remove(header::AUTHORIZATION);
+            if let Some(backend) = backend {
+                backend.remove_headers(&mut headers);
+            }
+            if let Some(authority) = next.authority() {
+                if let Ok(host) = authority.as_str().parse() {
+                    headers.insert(header::HOST, host);
+                }
+            }
+        }
+        uri = next;
+    }
+}
+
+/// `path_and_query` with every query parameter named in `names` removed,
+/// wherever it appears, and the remaining parameters kept in their original
+/// order. Names are compared decoded, the way `Query` reads them, so an
+/// encoded `api%2Dkey` is stripped too.
+fn strip_query_params<S: AsRef<str>>(path_and_query: &str, names: &[S]) -> String {
+    let Some((path, query)) = path_and_query.split_once('?') else {
+        return path_and_query.to_string();
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|param| {
+            let name = param.split_once('=').map_or(*param, |(name, _)| name);
+            let name = form_urlencoded::parse(name.as_bytes())
+                .next()
+                .map(|(name, _)| name)
+                .unwrap_or_default();
+            !names.iter().any(|stripped| stripped.as_ref() == name)
+        })
+        .collect();
+    if kept.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, kept.join("&"))
+    }
+}
+
+/// Update the Host header to match the backend at `uri`, unless the backend
+/// routes on the original Host. Returns the host if it isn't a valid header.
+fn set_upstream_host(
+    headers: &mut HeaderMap,
+    uri: &Uri,
+    preserve_host: bool,
+) -> Result<(), String> {
+    let Some(host) = uri.host().filter(|_| !preserve_host) else {
+        return Ok(());
+    };
+    let host_value = match uri.port_u16() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let host = HeaderValue::from_str(&host_value).map_err(|_| host_value)?;
+    headers.insert(header::HOST, host);
+    Ok(())
+}
+
+/// `uri` without its query string, which may carry a provider's API key
+fn without_query(uri: &Uri) -> String {
+    let uri = uri.to_string();
+    match uri.split_once('?') {
+        Some((base, _)) => base.to_string(),
+        None => uri,
+    }
+}
+
+/// Resolve a `Location` header against the URI that produced it
+fn resolve_redirect(base: &Uri, location: &str) -> Option<Uri> {
+    let location: Uri = location.parse().ok()?;
+    if location.scheme().is_some() {
+        return Some(location);
+    }
+    let mut parts = base.clone().into_parts();
+    parts.path_and_query = location.path_and_query().cloned();
+    Uri::from_parts(parts).ok()
+}
+
+/// 429 response for a client that exceeded a rate limit: a JSON-RPC error
+/// with the configured message, carrying the limit and window in `data`, and
+/// a `Retry-After` computed from the limiter's refill time
+fn rate_limited_response(message: &str, req: &Request<Body>, limited: RateLimited) -> Response {
+    let retry_after_secs = limited.retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut body = jsonrpc_error(RATE_LIMITED_ERROR, message, buffered_request_id(req));
+    body["error"]["data"] = serde_json::json!({
+        "limit": limited.limit,
+        "window_secs": 1,
+        "retry_after_ms": limited.retry_after.as_millis() as u64,
+    });
+
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, retry_after_secs.to_string())],
+        Json(body),
+    )
+        .into_response()
+}
+
+fn rate_limited_backends_response(retry_after: Duration, id: serde_json::Value) -> Response {
+    let retry_after_secs = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, retry_after_secs.to_string())],
+        Json(jsonrpc_error(
+            SERVER_ERROR,
+            "All backends are rate limited",
+            id,
+        )),
+    )
+        .into_response()
+}
+
+/// A JSON-RPC error response object
+fn jsonrpc_error(code: i64, message: &str, id: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+        },
+    })
+}
+
+fn error_response(status: StatusCode, code: i64, message: &str, id: serde_json::Value) -> Response {
+    (status, Json(jsonrpc_error(code, message, id))).into_response()
+}
+
+/// The `id` of a JSON-RPC request body (or of the single request in a batch
+/// of one), or null if there is none
+fn jsonrpc_request_id(body: &[u8]) -> serde_json::Value {
+    serde_json::from_slice::<serde_json::Value>(body)
+        .ok()
+        .map(unwrap_batch_of_one)
+        .and_then(|json| json.get("id").cloned())
+        .unwrap_or(serde_json::Value::Null)
+}
 
-#[derive(Deserialize)]
-pub struct Params {
-    #[serde(rename = "api-key")]
-    pub api_key: Option<String>,
+fn buffered_request_id(req: &Request<Body>) -> serde_json::Value {
+    req.extensions()
+        .get::<BufferedBody>()
+        .map(|body| jsonrpc_request_id(&body.0))
+        .unwrap_or(serde_json::Value::Null)
 }
 
-pub async fn extract_rpc_method(mut req: Request<Body>, next: Next) -> Response {
-    // Read body, extract "method" field, then reconstruct the request
-    let (parts, body) = req.into_parts();
-    let body_bytes = match to_bytes(body, MAX_BODY_SIZE).await {
-        Ok(bytes) => bytes,
-        Err(_) => {
-            // If body read fails, pass empty body downstream
-            return next.run(Request::from_parts(parts, Body::empty())).await;
-        }
-    };
+/// Wrap a response body so that streaming it aborts once `limit` has elapsed
+fn limit_body_duration(body: Body, limit: Duration, backend_label: String) -> Body {
+    let deadline = Instant::now() + limit;
+    let chunks = body.into_data_stream();
 
-    // Try to extract "method" from JSON
-    if let Ok(json) = serde_json::from_slice::<serde_json::Value>(&body_bytes) {
-        if let Some(method) = json.get("method").and_then(|m| m.as_str()) {
-            req = Request::from_parts(parts, Body::from(body_bytes.clone()));
-            req.extensions_mut().insert(RpcMethod(method.to_string()));
-            return next.run(req).await;
+    Body::from_stream(stream::unfold(Some(chunks), move |chunks| {
+        let backend_label = backend_label.clone();
+        async move {
+            let mut chunks = chunks?;
+            match timeout_at(deadline, chunks.next()).await {
+                Ok(Some(Ok(bytes))) => Some((Ok::<Bytes, axum::BoxError>(bytes), Some(chunks))),
+                Ok(Some(Err(e))) => Some((Err(e.into()), None)),
+                Ok(None) => None,
+                Err(_) => {
+                    error!(
+                        "Response from backend {} exceeded stream timeout of {}s, aborting",
+                        backend_label,
+                        limit.as_secs()
+                    );
+                    Some((Err("response stream timeout".into()), None))
+                }
+            }
         }
-    }
-
-    // If no method found, reconstruct request with original body
-    req = Request::from_parts(parts, Body::from(body_bytes));
-    next.run(req).await
+    }))
 }
 
-pub async fn log_requests(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    req: Request<Body>,
-    next: Next,
-) -> Response {
-    let method = req.method().clone();
-    let path = req.uri().path().to_string();
-    let rpc_method = req.extensions().get::<RpcMethod>().cloned();
+#[derive(Serialize)]
+pub struct HealthResponse {
+    pub overall_status: String,
+    pub backends: Vec<BackendHealth>,
+}
 
-    let start = std::time::Instant::now();
-    let response = next.run(req).await;
-    let duration = start.elapsed();
+#[derive(Serialize)]
+pub struct BackendHealth {
+    pub label: String,
+    pub healthy: bool,
+    /// "healthy", "unhealthy", or "healthy (checks disabled)"
+    pub status: &'static str,
+    pub last_check: Option<String>,
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    pub last_error: Option<String>,
+    pub last_error_category: Option<ErrorCategory>,
+    /// JSON-RPC method the active health check probes this backend with
+    pub health_method: String,
+    /// Slot reported by the most recent probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_slot: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rps: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub available_tokens: Option<f64>,
+    /// Seconds until a draining backend stops receiving new requests
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub drain_remaining_secs: Option<f64>,
+    /// Data-plane circuit breaker state, when breakers are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub breaker_state: Option<BreakerState>,
+    /// Average time to response headers, once the backend has served traffic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ewma_ms: Option<f64>,
+    /// Weight used for selection after adaptive scaling
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub effective_weight: Option<f64>,
+    /// Fraction of its weight a backend gets while warming up, when
+    /// slow-start is enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warmup_factor: Option<f64>,
+    /// Endpoints currently resolved for SRV backends, as `host:port`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub srv_targets: Option<Vec<String>>,
+}
 
-    // Extract backend from response extensions (set by proxy handler)
-    let backend = response.extensions().get::<SelectedBackend>().cloned();
+pub async fn metrics_endpoint(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    for (label, bucket) in &state.routing.load().backend_rate_limiters {
+        metrics::record_rate_tokens(label, bucket.available());
+    }
+    let body = state
+        .prometheus
+        .as_ref()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default();
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
 
-    match (rpc_method, backend) {
-        (Some(RpcMethod(m)), Some(SelectedBackend(b))) => info!(
-            "{} {} {} {:?} rpc_method={} backend={}",
-            method, path, addr, duration, m, b
-        ),
-        (Some(RpcMethod(m)), None) => info!(
-            "{} {} {} {:?} rpc_method={}",
-            method, path, addr, duration, m
-        ),
-        (None, Some(SelectedBackend(b))) => {
-            info!("{} {} {} {:?} backend={}", method, path, addr, duration, b)
+/// Check the `api-key` query param against the configured admin key. Admin
+/// endpoints are disabled entirely when no admin key is configured.
+fn check_admin(state: &AppState, params: &Params) -> Result<(), StatusCode> {
+    match (&state.admin_api_key, &params.api_key) {
+        (Some(admin_key), Some(key)) if bool::from(admin_key.as_bytes().ct_eq(key.as_bytes())) => {
+            Ok(())
         }
-        (None, None) => info!("{} {} {} {:?}", method, path, addr, duration),
+        (Some(_), _) => Err(StatusCode::UNAUTHORIZED),
+        (None, _) => Err(StatusCode::NOT_FOUND),
     }
-
-    response
 }
 
-pub async fn proxy(
+pub async fn admin_api_keys(
     State(state): State<Arc<AppState>>,
     Query(params): Query<Params>,
-    mut req: Request<Body>,
-) -> impl IntoResponse {
-    match params.api_key {
-        Some(ref key) if state.api_keys.contains(key) => {}
-        Some(ref key) => {
-            info!("API key '{}' is invalid", key);
-            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-        }
-        None => {
-            info!("No API key provided");
-            return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
-        }
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
     }
+    Json(state.api_key_metrics.snapshot()).into_response()
+}
 
-    // Get RPC method from extension (set by extract_rpc_method middleware)
-    let rpc_method = req.extensions().get::<RpcMethod>().map(|m| m.0.as_str());
+#[derive(Deserialize)]
+pub struct TopParams {
+    pub limit: Option<usize>,
+}
 
-    // Select backend based on method routing or weighted random
-    let (backend_label, backend_url) = match state.select_backend(rpc_method) {
-        Some(selection) => selection,
-        None => {
-            tracing::error!("No healthy backends available for request");
-            return (
-                StatusCode::SERVICE_UNAVAILABLE,
-                "No healthy backends available",
-            )
-                .into_response();
-        }
-    };
+/// Busiest JSON-RPC methods by request count
+pub async fn admin_methods(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<Params>,
+    Query(top): Query<TopParams>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    Json(state.method_metrics.top(top.limit.unwrap_or(20))).into_response()
+}
 
-    // Rebuild URI (remove ?api-key=... from request)
-    let request_path_and_query = req
-        .uri()
-        .path_and_query()
-        .map(|x| x.as_str())
-        .unwrap_or("/");
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub backends: BTreeMap<String, UsageStats>,
+    pub methods: BTreeMap<String, UsageStats>,
+}
 
-    // Remove api-key from the incoming request's query parameters
-    let cleaned_request_path = if let Some(pos) = request_path_and_query.find("?api-key=") {
-        &request_path_and_query[..pos]
-    } else {
-        request_path_and_query
-    };
+/// Cumulative request and error counts, average latency and time of last use
+/// per backend (upstream attempts) and per JSON-RPC method (client requests)
+pub async fn admin_stats(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<Params>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    Json(StatsResponse {
+        backends: state.backend_metrics.stats(),
+        methods: state.method_metrics.stats(),
+    })
+    .into_response()
+}
 
-    // Build URI with selected backend
-    let uri_string = if cleaned_request_path == "/" {
-        // For root path requests, don't add trailing slash
-        backend_url.trim_end_matches('/').to_string()
-    } else if backend_url.ends_with('/') && cleaned_request_path.starts_with('/') {
-        // Avoid double slashes
-        format!("{}{}", backend_url, &cleaned_request_path[1..])
-    } else {
-        format!("{}{}", backend_url, cleaned_request_path)
-    };
-    let parsed_uri = uri_string.parse::<Uri>().unwrap();
+pub async fn admin_recent(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<Params>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    Json(state.recent_requests.snapshot()).into_response()
+}
 
-    // Update Host header to match the backend
-    if let Some(host) = parsed_uri.host() {
-        let host_value = if let Some(port) = parsed_uri.port_u16() {
-            format!("{}:{}", host, port)
-        } else {
-            host.to_string()
-        };
-        req.headers_mut()
-            .insert("host", host_value.parse().unwrap());
+/// Close a backend's circuit breaker without waiting out the cooldown, e.g.
+/// once a provider incident is confirmed resolved
+pub async fn admin_reset_breaker(
+    State(state): State<Arc<AppState>>,
+    Path(label): Path<String>,
+    Query(params): Query<Params>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    let Some(ref breakers) = state.circuit_breakers else {
+        return (StatusCode::NOT_FOUND, "Circuit breakers are not enabled").into_response();
+    };
+    if state.backend(&label).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Unknown backend '{}'", label),
+        )
+            .into_response();
     }
 
-    *req.uri_mut() = parsed_uri;
+    let previous = breakers.reset(&label);
+    info!(
+        "Circuit breaker for backend {} manually reset (was {:?})",
+        label, previous
+    );
+    Json(serde_json::json!({
+        "label": label,
+        "previous_state": previous,
+        "state": BreakerState::Closed,
+    }))
+    .into_response()
+}
 
-    // Forward request
-    let result = timeout(
-        Duration::from_secs(state.proxy_timeout_secs),
-        state.client.request(req),
-    )
-    .await;
+/// Start draining a backend: its weight ramps down to zero over
+/// `drain_grace_secs`, after which it receives no new requests
+pub async fn admin_drain_backend(
+    State(state): State<Arc<AppState>>,
+    Path(label): Path<String>,
+    Query(params): Query<Params>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    if state.backend(&label).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Unknown backend '{}'", label),
+        )
+            .into_response();
+    }
+    let started = state.drains.start(&label);
+    Json(serde_json::json!({
+        "label": label,
+        "started": started,
+        "remaining_secs": state.drains.remaining(&label).map(|d| d.as_secs_f64()),
+    }))
+    .into_response()
+}
 
-    match result {
-        Ok(Ok(mut resp)) => {
-            // Store selected backend label in response extensions for logging
-            resp.extensions_mut()
-                .insert(SelectedBackend(backend_label.to_string()));
-            resp.into_response()
-        }
-        Ok(Err(err)) => {
-            info!("Backend request failed: {} (error type: {:?})", err, err);
-            (StatusCode::BAD_GATEWAY, format!("Proxy error: {}", err)).into_response()
-        }
-        Err(_) => (
-            StatusCode::GATEWAY_TIMEOUT,
-            format!(
-                "Upstream request timed out after {}s",
-                state.proxy_timeout_secs
-            ),
+/// Return a draining backend to full weight
+pub async fn admin_undrain_backend(
+    State(state): State<Arc<AppState>>,
+    Path(label): Path<String>,
+    Query(params): Query<Params>,
+) -> Response {
+    if let Err(status) = check_admin(&state, &params) {
+        return status.into_response();
+    }
+    if state.backend(&label).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Unknown backend '{}'", label),
         )
-            .into_response(),
+            .into_response();
+    }
+    let was_draining = state.drains.cancel(&label);
+    if was_draining {
+        info!("Backend {} returned to full weight", label);
     }
+    Json(serde_json::json!({ "label": label, "was_draining": was_draining })).into_response()
 }
 
-#[derive(Serialize)]
-pub struct HealthResponse {
-    pub overall_status: String,
-    pub backends: Vec<BackendHealth>,
+/// Liveness probe: responds as long as the process is serving requests
+pub async fn livez() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
 }
 
-#[derive(Serialize)]
-pub struct BackendHealth {
-    pub label: String,
-    pub healthy: bool,
-    pub last_check: Option<String>,
-    pub consecutive_failures: u32,
-    pub consecutive_successes: u32,
-    pub last_error: Option<String>,
+/// Readiness probe: ready while at least one backend is healthy
+pub async fn readyz(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let routing = state.routing.load();
+    let ready = routing.backends.iter().any(|backend| {
+        state
+            .health_state
+            .get_status(&backend.label)
+            .map(|status| status.healthy)
+            .unwrap_or(true)
+    });
+    if ready {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "no healthy backends")
+    }
 }
 
-pub async fn health_endpoint(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+#[derive(Deserialize)]
+pub struct HealthParams {
+    /// Report unavailable if any backend is unhealthy, not only all of them
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Detailed backend health. Responds 503 when every backend is unhealthy, or
+/// with `?strict=true` when any is, so HTTP health checks see the proxy as down.
+pub async fn health_endpoint(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HealthParams>,
+) -> impl IntoResponse {
     let all_statuses = state.health_state.get_all_statuses();
 
     let mut backends = Vec::new();
     let mut any_healthy = false;
+    let mut any_unhealthy = false;
 
-    for backend in &state.backends {
+    let routing = state.routing.load_full();
+    for backend in &routing.backends {
         let status = all_statuses
             .get(&backend.label)
             .cloned()
@@ -223,49 +1967,186 @@ pub async fn health_endpoint(State(state): State<Arc<AppState>>) -> impl IntoRes
 
         if status.healthy {
             any_healthy = true;
+        } else {
+            any_unhealthy = true;
         }
 
+        let unchecked = state.health_state.is_unchecked(&backend.label);
         backends.push(BackendHealth {
             label: backend.label.clone(),
             healthy: status.healthy,
+            status: if unchecked {
+                "healthy (checks disabled)"
+            } else if status.healthy {
+                "healthy"
+            } else {
+                "unhealthy"
+            },
             last_check: status.last_check_time.map(|t| format!("{:?}", t)),
             consecutive_failures: status.consecutive_failures,
             consecutive_successes: status.consecutive_successes,
             last_error: status.last_error,
+            last_error_category: status.last_error_category,
+            health_method: backend.health_method(&state.health_check).to_string(),
+            last_slot: status.last_slot,
+            max_rps: backend.max_rps,
+            available_tokens: routing
+                .backend_rate_limiters
+                .get(&backend.label)
+                .map(|bucket| bucket.available()),
+            drain_remaining_secs: state
+                .drains
+                .remaining(&backend.label)
+                .map(|d| d.as_secs_f64()),
+            breaker_state: state
+                .circuit_breakers
+                .as_ref()
+                .map(|breakers| breakers.state(&backend.label)),
+            latency_ewma_ms: state.latency.get(&backend.label),
+            effective_weight: state
+                .success_rate_weights
+                .as_ref()
+                .map(|_| state.effective_weight(backend)),
+            warmup_factor: state.warmup.map(|_| state.warmup_factor(&backend.label)),
+            srv_targets: backend.srv.as_ref().map(|_| {
+                state
+                    .srv_endpoints
+                    .targets(&backend.label)
+                    .iter()
+                    .map(|t| format!("{}:{}", t.host, t.port))
+                    .collect()
+            }),
         });
     }
 
     let overall_status = if any_healthy { "healthy" } else { "unhealthy" };
+    let status = if !any_healthy || (params.strict && any_unhealthy) {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
 
     let response = HealthResponse {
         overall_status: overall_status.to_string(),
         backends,
     };
 
-    Json(response)
+    (status, Json(response))
+}
+
+/// JSON-RPC over GET. With `allow_get`, `?method=` naming one of
+/// `get_methods` (and an optional JSON array in `?params=`) is proxied as if
+/// it had been POSTed; anything else gets a 405 explaining the POST usage.
+pub async fn get_proxy(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<HashMap<String, String>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request<Body>,
+) -> Response {
+    let Some(method) = query
+        .get("method")
+        .filter(|method| state.allow_get && state.get_methods.contains(*method))
+        .cloned()
+    else {
+        let message = if state.allow_get {
+            format!(
+                "GET requires ?method= with one of: {}. Send other requests as a JSON-RPC POST",
+                state.get_methods.join(", ")
+            )
+        } else {
+            "Send requests as a JSON-RPC POST, e.g. {\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"getSlot\"}"
+                .to_string()
+        };
+        let mut resp = error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            INVALID_REQUEST_ERROR,
+            &message,
+            serde_json::Value::Null,
+        );
+        resp.headers_mut()
+            .insert(header::ALLOW, HeaderValue::from_static("POST"));
+        return resp;
+    };
+
+    let mut request = serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": method});
+    if let Some(params) = query.get("params") {
+        match serde_json::from_str(params) {
+            Ok(params @ serde_json::Value::Array(_)) => request["params"] = params,
+            _ => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    INVALID_PARAMS_ERROR,
+                    "params must be a JSON array",
+                    serde_json::json!(1),
+                )
+            }
+        }
+    }
+    let body = Bytes::from(request.to_string());
+    let parsed = ParsedBody(Arc::new(request));
+
+    // Forward as the equivalent POST, without the GET-only query parameters
+    let (mut parts, _) = req.into_parts();
+    parts.method = axum::http::Method::POST;
+    if let Some(path_and_query) = parts.uri.path_and_query() {
+        if let Ok(uri) = strip_query_params(path_and_query.as_str(), &["method", "params"]).parse()
+        {
+            parts.uri = uri;
+        }
+    }
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    parts
+        .headers
+        .insert(header::CONTENT_LENGTH, body.len().into());
+    parts.extensions.insert(RpcMethod(method));
+    parts.extensions.insert(BufferedBody(body.clone()));
+    parts.extensions.insert(parsed);
+    let req = Request::from_parts(parts, Body::from(body));
+    proxy(State(state), Query(query), ConnectInfo(addr), req)
+        .await
+        .into_response()
 }
 
+/// WebSocket connections, proxied to a backend's `ws_url`. Plain GETs
+/// without an upgrade are handled by `get_proxy`.
 pub async fn ws_proxy(
-    ws: WebSocketUpgrade,
+    ws: Option<WebSocketUpgrade>,
     State(state): State<Arc<AppState>>,
-    Query(params): Query<Params>,
+    Query(query): Query<HashMap<String, String>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
+    req: Request<Body>,
+) -> Response {
+    let Some(ws) = ws else {
+        return get_proxy(State(state), Query(query), ConnectInfo(addr), req).await;
+    };
     // Validate API key
-    match params.api_key {
-        Some(ref key) if state.api_keys.contains(key) => {}
-        Some(ref key) => {
-            info!("WebSocket: API key '{}' is invalid from {}", key, addr);
+    let api_keys = state.routing.load().api_keys.clone();
+    let candidate =
+        client_api_key(&state.key_params, &query, req.headers()).map(|key| key.map(str::to_string));
+    let key_id = match candidate {
+        Ok(Some(key)) => match api_keys.verify_request(&key).await {
+            Some(key_id) => key_id,
+            None => {
+                info!("WebSocket: API key '{}' is invalid from {}", key, addr);
+                return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+            }
+        },
+        Ok(None) => {
+            info!("WebSocket: No API key provided from {}", addr);
             return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
         }
-        None => {
-            info!("WebSocket: No API key provided from {}", addr);
+        Err(reason) => {
+            info!("WebSocket: {} from {}", reason, addr);
             return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
         }
-    }
+    };
 
-    // Select a backend with WebSocket support
-    let (backend_label, backend_ws_url) = match state.select_ws_backend() {
+    // Select a backend with WebSocket support that the key may use
+    let allowed_backends = state.routing.load().key_backends.get(&key_id).cloned();
+    let (backend_label, backend_ws_url) = match state.select_ws_backend(allowed_backends.as_ref()) {
         Some(selection) => selection,
         None => {
             error!("No healthy WebSocket backends available");
@@ -277,37 +2158,64 @@ pub async fn ws_proxy(
         }
     };
 
-    let backend_label = backend_label.to_string();
-    let backend_ws_url = backend_ws_url.to_string();
+    // The provider's credentials go on the handshake, as for HTTP requests
+    let backend = state.backend(&backend_label);
+    let backend_ws_url = match backend {
+        Some(ref backend) => backend.authenticated_url(&backend_ws_url),
+        None => backend_ws_url,
+    };
+    let mut backend_request = match backend_ws_url.as_str().into_client_request() {
+        Ok(request) => request,
+        Err(e) => {
+            error!(
+                "WebSocket: Backend {} has an invalid ws_url: {}",
+                backend_label, e
+            );
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Invalid backend URL").into_response();
+        }
+    };
+    if let Some(ref backend) = backend {
+        backend.insert_headers(backend_request.headers_mut());
+    }
 
     info!(
         "WebSocket: {} upgrading connection, backend={}",
         addr, backend_label
     );
 
+    let connector = state.clients.ws_connector(&backend_label);
     ws.on_upgrade(move |client_socket| {
-        handle_ws_connection(client_socket, backend_ws_url, backend_label, addr)
+        handle_ws_connection(
+            client_socket,
+            backend_request,
+            connector,
+            backend_label,
+            addr,
+        )
     })
     .into_response()
 }
 
 async fn handle_ws_connection(
     client_socket: WebSocket,
-    backend_url: String,
+    backend_request: WsRequest,
+    connector: Option<Connector>,
     backend_label: String,
     client_addr: SocketAddr,
 ) {
     // Connect to the backend WebSocket
-    let backend_socket = match connect_async(&backend_url).await {
-        Ok((socket, _)) => socket,
-        Err(e) => {
-            error!(
-                "WebSocket: Failed to connect to backend {} ({}): {}",
-                backend_label, backend_url, e
-            );
-            return;
-        }
-    };
+    let backend_url = without_query(backend_request.uri());
+    let backend_socket =
+        match connect_async_tls_with_config(backend_request, None, false, connector).await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                error!(
+                    "WebSocket: Failed to connect to backend {} ({}): {}",
+                    backend_label, backend_url, e
+                );
+                return;
+            }
+        };
 
     info!(
         "WebSocket: {} connected to backend {}",
@@ -368,38 +2276,22 @@ async fn handle_ws_connection(
         while let Some(msg) = backend_read.next().await {
             match msg {
                 Ok(TungsteniteMessage::Text(text)) => {
-                    if client_write
-                        .send(Message::Text(text))
-                        .await
-                        .is_err()
-                    {
+                    if client_write.send(Message::Text(text)).await.is_err() {
                         break;
                     }
                 }
                 Ok(TungsteniteMessage::Binary(data)) => {
-                    if client_write
-                        .send(Message::Binary(data))
-                        .await
-                        .is_err()
-                    {
+                    if client_write.send(Message::Binary(data)).await.is_err() {
                         break;
                     }
                 }
                 Ok(TungsteniteMessage::Ping(data)) => {
-                    if client_write
-                        .send(Message::Ping(data))
-                        .await
-                        .is_err()
-                    {
+                    if client_write.send(Message::Ping(data)).await.is_err() {
                         break;
                     }
                 }
                 Ok(TungsteniteMessage::Pong(data)) => {
-                    if client_write
-                        .send(Message::Pong(data))
-                        .await
-                        .is_err()
-                    {
+                    if client_write.send(Message::Pong(data)).await.is_err() {
                         break;
                     }
                 }
@@ -421,3 +2313,285 @@ async fn handle_ws_connection(
         client_addr, backend_label
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use arc_swap::ArcSwap;
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        client::{BackendClients, ClientOptions},
+        config::Config,
+        dead_letter::DeadLetterLog,
+        discovery::SrvEndpoints,
+        health::HealthState,
+        rate_limit::KeyRateLimiter,
+        state::Routing,
+    };
+
+    fn test_state(toml: &str) -> AppState {
+        let config: Config = toml::from_str(toml).unwrap();
+        let labels = config.backends.iter().map(|b| b.label.clone()).collect();
+        let options = ClientOptions {
+            nodelay: true,
+            keepalive: None,
+            pool_max_idle_per_host: 1,
+            pool_idle_timeout: Duration::from_secs(1),
+            http2_only: false,
+        };
+        let dead_letter = config
+            .proxy
+            .dead_letter_path
+            .as_deref()
+            .map(|path| DeadLetterLog::open(path).unwrap());
+        AppState::new(
+            &config,
+            Arc::new(ArcSwap::from_pointee(Routing::new(&config))),
+            Arc::new(HealthState::new(labels)),
+            Arc::new(BackendClients::new(&config, options)),
+            Arc::new(SrvEndpoints::new()),
+            dead_letter,
+            None,
+        )
+    }
+
+    /// Address of a local upstream answering every POST with `status`
+    async fn upstream(status: StatusCode) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", post(move || async move { status }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    #[test]
+    fn batch_elements_each_take_a_method_rate_limit_token() {
+        let state = test_state(
+            r#"
+            port = 28899
+            backends = []
+            [method_rate_limits]
+            getProgramAccounts = { rps = 2 }
+            "#,
+        );
+        let batch = |methods: &[&str]| {
+            let mut req = Request::post("/").body(Body::empty()).unwrap();
+            req.extensions_mut().insert(BatchMethods(
+                methods.iter().map(|method| method.to_string()).collect(),
+            ));
+            req
+        };
+
+        let unlimited = batch(&["getSlot", "getBalance", "getSlot"]);
+        assert!(method_rate_limited(&state, &unlimited, "key").is_none());
+
+        let over = batch(&[
+            "getProgramAccounts",
+            "getSlot",
+            "getProgramAccounts",
+            "getProgramAccounts",
+        ]);
+        let (method, limited) = method_rate_limited(&state, &over, "key").unwrap();
+        assert_eq!(method, "getProgramAccounts");
+        assert_eq!(limited.limit, 2);
+
+        let response = rate_limited_response("Rate limit exceeded", &over, limited);
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn open_circuit_does_not_spend_rate_tokens() {
+        let state = test_state(
+            r#"
+            port = 28899
+            [proxy]
+            breaker_threshold = 1
+            [[backends]]
+            label = "a"
+            url = "http://a.invalid"
+            weight = 1
+            max_rps = 1
+            "#,
+        );
+        state.record_attempt("a", Err(BackendError::Timeout(Duration::from_secs(1))));
+        assert!(!state.acquire_backend_capacity("a"));
+
+        state.circuit_breakers.as_ref().unwrap().reset("a");
+        assert!(state.acquire_backend_capacity("a"));
+        assert!(!state.acquire_backend_capacity("a"));
+    }
+
+    #[tokio::test]
+    async fn send_transaction_failing_on_every_backend_is_dead_lettered() {
+        let upstream = upstream(StatusCode::SERVICE_UNAVAILABLE).await;
+        let path = std::env::temp_dir().join(format!("dead-letter-{}.jsonl", std::process::id()));
+        let state = test_state(&format!(
+            r#"
+            port = 28899
+            [proxy]
+            max_retries = 5
+            dead_letter_path = "{}"
+            [[backends]]
+            label = "a"
+            url = "http://{upstream}"
+            weight = 1
+            [[backends]]
+            label = "b"
+            url = "http://{upstream}"
+            weight = 1
+            "#,
+            path.display()
+        ));
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"sendTransaction","params":["tx"]}"#;
+        let mut req = Request::post("/").body(Body::from(body)).unwrap();
+        req.extensions_mut()
+            .insert(RpcMethod("sendTransaction".to_string()));
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let resp = forward(&state, client, "key", req, &mut Diagnostics::default()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let entries: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["rpc_method"], "sendTransaction");
+        assert_eq!(
+            entries[0]["reason"],
+            "upstream returned 503 Service Unavailable"
+        );
+        assert_eq!(entries[0]["payload"]["params"][0], "tx");
+    }
+
+    const KEY_PARAMS: [&str; 1] = ["api-key"];
+
+    #[tokio::test]
+    async fn body_over_the_limit_gets_413() {
+        let state = Arc::new(test_state(
+            r#"
+            port = 28899
+            backends = []
+            [proxy]
+            max_body_bytes = 10485760
+            "#,
+        ));
+        let app = Router::new()
+            .route("/", post(|| async { StatusCode::OK }))
+            .layer(axum::middleware::from_fn_with_state(
+                state,
+                extract_rpc_method,
+            ));
+
+        let mut req = Request::post("/")
+            .body(Body::from(vec![b' '; 12 * 1024 * 1024]))
+            .unwrap();
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = to_bytes(resp.into_body(), usize::MAX).await.unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"]["code"], INVALID_REQUEST_ERROR);
+        assert_eq!(json["error"]["message"], "Request body too large");
+    }
+
+    fn client_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("rpc.vanity.example"));
+        headers
+    }
+
+    #[test]
+    fn upstream_host_is_rewritten_by_default() {
+        let mut headers = client_headers();
+        let uri: Uri = "https://provider.example:8443/v1".parse().unwrap();
+        set_upstream_host(&mut headers, &uri, false).unwrap();
+        assert_eq!(headers[header::HOST], "provider.example:8443");
+    }
+
+    #[test]
+    fn upstream_host_is_kept_with_preserve_host() {
+        let mut headers = client_headers();
+        let uri: Uri = "https://provider.example/v1".parse().unwrap();
+        set_upstream_host(&mut headers, &uri, true).unwrap();
+        assert_eq!(headers[header::HOST], "rpc.vanity.example");
+    }
+
+    #[test]
+    fn key_rate_limit_rejects_a_burst_past_its_limit_with_429() {
+        let limiter = KeyRateLimiter::new(HashMap::from([("key-id".to_string(), 5)]));
+        for _ in 0..5 {
+            assert!(limiter.check("key-id").is_ok());
+        }
+        let limited = limiter.check("key-id").unwrap_err();
+        assert_eq!(limited.limit, 5);
+        assert!(limited.retry_after > Duration::ZERO);
+        assert!(limited.retry_after <= Duration::from_millis(200));
+
+        let req = Request::new(Body::empty());
+        let resp = rate_limited_response("Rate limit exceeded", &req, limited);
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "1");
+
+        // Keys without a limit are never rejected
+        assert!((0..100).all(|_| limiter.check("other-key-id").is_ok()));
+    }
+
+    #[test]
+    fn key_rate_limit_refills_after_retry_after() {
+        let limiter = KeyRateLimiter::new(HashMap::from([("key-id".to_string(), 2)]));
+        while limiter.check("key-id").is_ok() {}
+        let limited = limiter.check("key-id").unwrap_err();
+        std::thread::sleep(limited.retry_after + Duration::from_millis(5));
+        assert!(limiter.check("key-id").is_ok());
+        assert!(limiter.check("key-id").is_err());
+    }
+
+    #[test]
+    fn strip_query_params_removes_key_first() {
+        assert_eq!(
+            strip_query_params("/?api-key=secret&commitment=finalized", &KEY_PARAMS),
+            "/?commitment=finalized"
+        );
+    }
+
+    #[test]
+    fn strip_query_params_removes_key_in_middle() {
+        assert_eq!(
+            strip_query_params("/rpc?a=1&api-key=secret&b=2", &KEY_PARAMS),
+            "/rpc?a=1&b=2"
+        );
+    }
+
+    #[test]
+    fn strip_query_params_removes_key_last() {
+        assert_eq!(
+            strip_query_params("/?a=1&api-key=secret", &KEY_PARAMS),
+            "/?a=1"
+        );
+        assert_eq!(strip_query_params("/?api-key=secret", &KEY_PARAMS), "/");
+    }
+
+    #[test]
+    fn strip_query_params_keeps_query_without_key() {
+        assert_eq!(
+            strip_query_params("/?a=1&api-keys=x&b=2", &KEY_PARAMS),
+            "/?a=1&api-keys=x&b=2"
+        );
+        assert_eq!(strip_query_params("/path", &KEY_PARAMS), "/path");
+    }
+
+    #[test]
+    fn strip_query_params_removes_encoded_key() {
+        assert_eq!(
+            strip_query_params("/?api%2Dkey=secret&a=1&api%2dkey", &KEY_PARAMS),
+            "/?a=1"
+        );
+    }
+}
@@ -1,23 +1,53 @@
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
-    time::SystemTime,
+    collections::{HashMap, HashSet},
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Instant, SystemTime},
 };
 
-use axum::{body::Body, http::Request};
-use hyper_tls::HttpsConnector;
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
-use tokio::time::{sleep, timeout, Duration};
+use arc_swap::ArcSwap;
+use axum::{
+    body::{to_bytes, Body},
+    http::Request,
+};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, sleep_until, timeout, Duration};
 
-use crate::config::{Backend, HealthCheckConfig};
+use crate::{
+    client::{BackendClients, HttpsClient},
+    config::{Backend, HealthCheckConfig},
+    discovery::SrvEndpoints,
+    state::Routing,
+};
 
-#[derive(Debug, Clone)]
+const MAX_HEALTH_RESPONSE_SIZE: usize = 1024 * 1024;
+
+/// Fraction of its weight a backend starts at when its warmup begins
+const MIN_WARMUP_FACTOR: f64 = 0.1;
+
+/// With `backoff_max_secs` set, how soon a backend whose health is changing
+/// is probed again
+const FAST_RECHECK: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BackendHealthStatus {
     pub healthy: bool,
     pub last_check_time: Option<SystemTime>,
     pub consecutive_failures: u32,
     pub consecutive_successes: u32,
     pub last_error: Option<String>,
+    #[serde(default)]
+    pub last_error_category: Option<ErrorCategory>,
+    /// Slot reported by the backend's most recent probe
+    #[serde(default)]
+    pub last_slot: Option<u64>,
+    /// Status was restored from a state file and not yet confirmed by a probe
+    #[serde(skip)]
+    pub restored: bool,
 }
 
 impl Default for BackendHealthStatus {
@@ -28,12 +58,26 @@ impl Default for BackendHealthStatus {
             consecutive_failures: 0,
             consecutive_successes: 0,
             last_error: None,
+            last_error_category: None,
+            last_slot: None,
+            restored: false,
         }
     }
 }
 
 pub struct HealthState {
     statuses: RwLock<HashMap<String, BackendHealthStatus>>,
+    /// Highest slot reported by a healthy backend in the last probe round,
+    /// 0 if unknown
+    latest_slot: AtomicU64,
+    /// Bumped whenever a backend turns healthy or unhealthy, or the set of
+    /// tracked backends changes
+    generation: AtomicU64,
+    /// When each backend last turned healthy (or was added by a reload), for
+    /// slow-start
+    healthy_since: Mutex<HashMap<String, Instant>>,
+    /// Backends with `health_check_enabled = false`, which are always healthy
+    unchecked: RwLock<HashSet<String>>,
 }
 
 impl HealthState {
@@ -44,7 +88,57 @@ impl HealthState {
         }
         Self {
             statuses: RwLock::new(statuses),
+            latest_slot: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            healthy_since: Mutex::new(HashMap::new()),
+            unchecked: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Track backends added by a config reload, starting out healthy
+    pub fn register(&self, backend_labels: &[String]) {
+        let mut statuses = self.statuses.write().unwrap();
+        let mut healthy_since = self.healthy_since.lock().unwrap();
+        for label in backend_labels {
+            if !statuses.contains_key(label) {
+                statuses.insert(label.clone(), BackendHealthStatus::default());
+                healthy_since.insert(label.clone(), Instant::now());
+            }
         }
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Record which backends have health checks disabled. They are marked
+    /// healthy and stay healthy, whatever probes, real traffic or a restored
+    /// state file say.
+    pub fn set_unchecked(&self, backends: &[Backend]) {
+        let unchecked: HashSet<String> = backends
+            .iter()
+            .filter(|b| !b.health_check_enabled)
+            .map(|b| b.label.clone())
+            .collect();
+        let mut statuses = self.statuses.write().unwrap();
+        for label in &unchecked {
+            if let Some(status) = statuses.get_mut(label) {
+                if !status.healthy {
+                    status.healthy = true;
+                    self.generation.fetch_add(1, Ordering::Release);
+                }
+            }
+        }
+        *self.unchecked.write().unwrap() = unchecked;
+    }
+
+    /// Whether the backend's health checks are disabled
+    pub fn is_unchecked(&self, label: &str) -> bool {
+        self.unchecked.read().unwrap().contains(label)
+    }
+
+    /// Stop tracking a backend removed by a config reload
+    pub fn remove(&self, label: &str) {
+        self.statuses.write().unwrap().remove(label);
+        self.healthy_since.lock().unwrap().remove(label);
+        self.generation.fetch_add(1, Ordering::Release);
     }
 
     pub fn get_status(&self, label: &str) -> Option<BackendHealthStatus> {
@@ -53,90 +147,658 @@ impl HealthState {
 
     pub fn update_status(&self, label: &str, status: BackendHealthStatus) {
         if let Some(s) = self.statuses.write().unwrap().get_mut(label) {
+            if s.healthy != status.healthy {
+                self.generation.fetch_add(1, Ordering::Release);
+            }
+            if !s.healthy && status.healthy {
+                self.healthy_since
+                    .lock()
+                    .unwrap()
+                    .insert(label.to_string(), Instant::now());
+            }
             *s = status;
         }
     }
 
+    /// Fraction of its weight a backend receives during slow-start: it ramps
+    /// linearly from `MIN_WARMUP_FACTOR` to 1.0 over `warmup` after the
+    /// backend turned healthy
+    pub fn warmup_factor(&self, label: &str, warmup: Duration) -> f64 {
+        match self.healthy_since.lock().unwrap().get(label) {
+            Some(since) if since.elapsed() < warmup => {
+                let progress = since.elapsed().as_secs_f64() / warmup.as_secs_f64();
+                MIN_WARMUP_FACTOR + (1.0 - MIN_WARMUP_FACTOR) * progress
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Whether any backend is still warming up
+    pub fn is_warming(&self, warmup: Duration) -> bool {
+        self.healthy_since
+            .lock()
+            .unwrap()
+            .values()
+            .any(|since| since.elapsed() < warmup)
+    }
+
+    /// Current health generation; selection structures built from the
+    /// statuses are stale once this changes
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Recompute the latest slot from the backends that are healthy now, so
+    /// a slot reported by a backend that has since failed or been removed
+    /// stops counting
+    pub fn refresh_latest_slot(&self) {
+        let latest = self
+            .statuses
+            .read()
+            .unwrap()
+            .values()
+            .filter(|status| status.healthy)
+            .filter_map(|status| status.last_slot)
+            .max()
+            .unwrap_or(0);
+        self.latest_slot.store(latest, Ordering::Relaxed);
+    }
+
+    /// Highest slot reported by a healthy backend, 0 if none is known
+    pub fn latest_slot(&self) -> u64 {
+        self.latest_slot.load(Ordering::Relaxed)
+    }
+
     pub fn get_all_statuses(&self) -> HashMap<String, BackendHealthStatus> {
         self.statuses.read().unwrap().clone()
     }
+
+    /// Record a failure observed on real traffic. The backend is marked
+    /// unhealthy once `failures_threshold` consecutive failures accumulate;
+    /// the active checker marks it healthy again once probes succeed.
+    /// Returns true if this failure made the backend unhealthy.
+    pub fn record_failure(
+        &self,
+        label: &str,
+        error: HealthCheckError,
+        failures_threshold: u32,
+    ) -> bool {
+        let mut statuses = self.statuses.write().unwrap();
+        let Some(status) = statuses.get_mut(label) else {
+            return false;
+        };
+        status.consecutive_failures += 1;
+        status.consecutive_successes = 0;
+        status.last_error = Some(error.message);
+        status.last_error_category = Some(error.category);
+        if self.unchecked.read().unwrap().contains(label) {
+            return false;
+        }
+        if status.healthy && status.consecutive_failures >= failures_threshold {
+            status.healthy = false;
+            self.generation.fetch_add(1, Ordering::Release);
+            tracing::warn!(
+                "Backend {} marked as UNHEALTHY after {} consecutive failures",
+                label,
+                status.consecutive_failures
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Record a failure observed on real traffic that doesn't count toward
+    /// marking the backend unhealthy, so `/health` still shows the latest error
+    pub fn record_error(&self, label: &str, error: HealthCheckError) {
+        if let Some(status) = self.statuses.write().unwrap().get_mut(label) {
+            status.last_error = Some(error.message);
+            status.last_error_category = Some(error.category);
+        }
+    }
+
+    /// Restore statuses from a state file written by `save_to_file`.
+    /// Only labels that are still configured are restored. Returns the
+    /// number of backends restored.
+    pub fn load_from_file(&self, path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+        let contents = fs::read_to_string(path)?;
+        let saved: HashMap<String, BackendHealthStatus> = serde_json::from_str(&contents)?;
+
+        let mut statuses = self.statuses.write().unwrap();
+        let mut restored = 0;
+        for (label, mut status) in saved {
+            if let Some(s) = statuses.get_mut(&label) {
+                status.restored = true;
+                *s = status;
+                restored += 1;
+            }
+        }
+        self.generation.fetch_add(1, Ordering::Release);
+        Ok(restored)
+    }
+
+    /// Write all statuses to `path`, replacing the previous file atomically
+    pub fn save_to_file(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::to_vec_pretty(&self.get_all_statuses())?;
+        let tmp_path = format!("{}.tmp", path);
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// Failure category of a health check, for grouping failures in alerting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Timeout,
+    ConnectionRefused,
+    Dns,
+    Tls,
+    Connection,
+    HttpStatus,
+    JsonrpcError,
+    InvalidResponse,
+    SlotLag,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct HealthCheckError {
+    pub category: ErrorCategory,
+    pub message: String,
+}
+
+impl HealthCheckError {
+    pub fn new(category: ErrorCategory, message: String) -> Self {
+        Self { category, message }
+    }
+}
+
+impl std::fmt::Display for HealthCheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Why an upstream attempt on real traffic failed
+#[derive(Debug, Clone)]
+pub enum BackendError {
+    /// No response headers within the upstream timeout
+    Timeout(Duration),
+    /// Connection, DNS or TLS failure before any response
+    Connect(HealthCheckError),
+    /// A status listed in `retry_status_codes`, usually a 5xx
+    Upstream5xx(u16),
+    /// A 429 listed in `retry_status_codes`: the provider is throttling the
+    /// router, not failing
+    RateLimited,
+    /// The response body could not be read
+    BodyRead(String),
+    /// A redirect, or a missing result when `validate_result` is set
+    InvalidResponse(String),
+}
+
+impl BackendError {
+    /// A request that failed before any response arrived
+    pub fn connect(err: &(dyn std::error::Error + 'static)) -> Self {
+        BackendError::Connect(HealthCheckError::new(
+            categorize_request_error(err),
+            root_cause(err).to_string(),
+        ))
+    }
+
+    /// A retryable upstream status
+    pub fn status(status: u16) -> Self {
+        if status == 429 {
+            BackendError::RateLimited
+        } else {
+            BackendError::Upstream5xx(status)
+        }
+    }
+
+    /// Metric label of each kind, indexed by `index`
+    pub const KINDS: [&'static str; 6] = [
+        "timeout",
+        "connect",
+        "upstream_5xx",
+        "body_read",
+        "invalid_response",
+        "rate_limited",
+    ];
+
+    pub fn index(&self) -> usize {
+        match self {
+            BackendError::Timeout(_) => 0,
+            BackendError::Connect(_) => 1,
+            BackendError::Upstream5xx(_) => 2,
+            BackendError::BodyRead(_) => 3,
+            BackendError::InvalidResponse(_) => 4,
+            BackendError::RateLimited => 5,
+        }
+    }
+
+    /// Whether the failure counts toward the circuit breaker and the
+    /// failover trip. An upstream 429 means the backend is healthy but
+    /// throttled, so it only lowers the backend's adaptive weight.
+    pub fn trips_breaker(&self) -> bool {
+        !matches!(self, BackendError::RateLimited)
+    }
+
+    /// Whether the failure counts toward marking the backend unhealthy. A
+    /// backend that times out or returns errors is still reachable and is
+    /// left to the circuit breaker and adaptive weights; one that can't be
+    /// connected to, or answers with garbage, is treated as down.
+    pub fn marks_unhealthy(&self) -> bool {
+        matches!(
+            self,
+            BackendError::Connect(_) | BackendError::InvalidResponse(_)
+        )
+    }
+
+    /// The failure as reported in `/health`'s `last_error`
+    pub fn health_error(&self) -> HealthCheckError {
+        match self {
+            BackendError::Timeout(after) => HealthCheckError::new(
+                ErrorCategory::Timeout,
+                format!(
+                    "Upstream request timed out after {:.1}s",
+                    after.as_secs_f64()
+                ),
+            ),
+            BackendError::Connect(error) => error.clone(),
+            BackendError::Upstream5xx(status) => HealthCheckError::new(
+                ErrorCategory::HttpStatus,
+                format!("Upstream returned HTTP {}", status),
+            ),
+            BackendError::RateLimited => HealthCheckError::new(
+                ErrorCategory::HttpStatus,
+                "Upstream rate limited the request (HTTP 429)".to_string(),
+            ),
+            BackendError::BodyRead(message) => HealthCheckError::new(
+                ErrorCategory::Connection,
+                format!("Failed to read response body: {}", message),
+            ),
+            BackendError::InvalidResponse(message) => {
+                HealthCheckError::new(ErrorCategory::InvalidResponse, message.clone())
+            }
+        }
+    }
+}
+
+/// The innermost error of a source chain. hyper's own messages (e.g.
+/// "client error (Connect)") hide the actual cause.
+pub fn root_cause<'a>(
+    err: &'a (dyn std::error::Error + 'static),
+) -> &'a (dyn std::error::Error + 'static) {
+    let mut cause = err;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause
+}
+
+/// Classify a client error by walking its source chain. hyper wraps the
+/// underlying IO, DNS and TLS errors, so the category is only visible there.
+pub fn categorize_request_error(err: &(dyn std::error::Error + 'static)) -> ErrorCategory {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            match io.kind() {
+                std::io::ErrorKind::ConnectionRefused => return ErrorCategory::ConnectionRefused,
+                std::io::ErrorKind::TimedOut => return ErrorCategory::Timeout,
+                _ => {}
+            }
+        }
+        let message = e.to_string().to_lowercase();
+        if message.contains("dns error") || message.contains("failed to lookup address") {
+            return ErrorCategory::Dns;
+        }
+        if message.contains("tls") || message.contains("certificate") || message.contains("ssl") {
+            return ErrorCategory::Tls;
+        }
+        source = e.source();
+    }
+    ErrorCategory::Connection
+}
+
+/// Outcome of one probe: the slot reported by the backend, if any
+type ProbeResult = Result<Option<u64>, HealthCheckError>;
+
+/// Fail a probe whose backend responded but is more than `max_slot_lag`
+/// slots behind the highest slot reported by a healthy backend
+fn check_slot_lag(
+    result: ProbeResult,
+    health_state: &HealthState,
+    health_config: &HealthCheckConfig,
+) -> ProbeResult {
+    let (Ok(Some(slot)), Some(max_lag)) = (&result, health_config.max_slot_lag) else {
+        return result;
+    };
+    let lag = health_state.latest_slot().saturating_sub(*slot);
+    if lag > max_lag {
+        return Err(HealthCheckError::new(
+            ErrorCategory::SlotLag,
+            format!(
+                "Backend is {} slots behind the highest healthy slot (max_slot_lag: {})",
+                lag, max_lag
+            ),
+        ));
+    }
+    result
 }
 
 async fn perform_health_check(
-    client: &Client<HttpsConnector<HttpConnector>, Body>,
+    client: &HttpsClient,
     backend: &Backend,
+    url: &str,
     health_config: &HealthCheckConfig,
-) -> Result<(), String> {
+) -> ProbeResult {
     // Build health check request
     let health_request = serde_json::json!({
         "jsonrpc": "2.0",
         "id": 1,
-        "method": health_config.method,
+        "method": backend.health_method(health_config),
         "params": []
     });
 
-    let body_bytes = serde_json::to_vec(&health_request)
-        .map_err(|e| format!("Failed to serialize health check: {}", e))?;
+    let body_bytes = serde_json::to_vec(&health_request).map_err(|e| {
+        HealthCheckError::new(
+            ErrorCategory::Other,
+            format!("Failed to serialize health check: {}", e),
+        )
+    })?;
 
-    let req = Request::builder()
+    let mut req = Request::builder()
         .method("POST")
-        .uri(&backend.url)
+        .uri(backend.authenticated_url(url))
         .header("content-type", "application/json")
         .body(Body::from(body_bytes))
-        .map_err(|e| format!("Failed to build request: {}", e))?;
-
-    // Perform request with timeout
-    let result = timeout(
-        Duration::from_secs(health_config.timeout_secs),
-        client.request(req),
-    )
-    .await;
-
-    match result {
-        Ok(Ok(response)) => {
-            if response.status().is_success() {
-                Ok(())
-            } else {
-                Err(format!(
-                    "Health check returned status: {}",
-                    response.status()
-                ))
-            }
+        .map_err(|e| {
+            HealthCheckError::new(
+                ErrorCategory::Other,
+                format!("Failed to build request: {}", e),
+            )
+        })?;
+    backend.insert_headers(req.headers_mut());
+
+    // Perform request and read the response with timeout
+    let check = async {
+        let response = client.request(req).await.map_err(|e| {
+            HealthCheckError::new(
+                categorize_request_error(&e),
+                format!("Health check request failed: {}", e),
+            )
+        })?;
+
+        if !response.status().is_success() {
+            return Err(HealthCheckError::new(
+                ErrorCategory::HttpStatus,
+                format!("Health check returned status: {}", response.status()),
+            ));
         }
-        Ok(Err(e)) => Err(format!("Health check request failed: {}", e)),
-        Err(_) => Err(format!(
-            "Health check timed out after {}s",
-            health_config.timeout_secs
+
+        let body = to_bytes(Body::new(response.into_body()), MAX_HEALTH_RESPONSE_SIZE)
+            .await
+            .map_err(|e| {
+                HealthCheckError::new(
+                    ErrorCategory::InvalidResponse,
+                    format!("Failed to read health check response: {}", e),
+                )
+            })?;
+        let json: serde_json::Value = serde_json::from_slice(&body).map_err(|e| {
+            HealthCheckError::new(
+                ErrorCategory::InvalidResponse,
+                format!("Health check returned invalid JSON: {}", e),
+            )
+        })?;
+        if let Some(error) = json.get("error") {
+            return Err(HealthCheckError::new(
+                ErrorCategory::JsonrpcError,
+                format!("Health check returned JSON-RPC error: {}", error),
+            ));
+        }
+        // A numeric result (e.g. from getSlot) is the backend's current slot
+        Ok(json.get("result").and_then(|r| r.as_u64()))
+    };
+
+    match timeout(Duration::from_secs(health_config.timeout_secs), check).await {
+        Ok(result) => result,
+        Err(_) => Err(HealthCheckError::new(
+            ErrorCategory::Timeout,
+            format!(
+                "Health check timed out after {}s",
+                health_config.timeout_secs
+            ),
         )),
     }
 }
 
+/// Probe every backend once, concurrently, logging a summary table and
+/// seeding `health_state` with the results. Returns the number of backends
+/// that passed.
+pub async fn startup_probe(
+    clients: &BackendClients,
+    endpoints: &SrvEndpoints,
+    backends: &[Backend],
+    health_state: &HealthState,
+    health_config: &HealthCheckConfig,
+) -> usize {
+    let results: Vec<(&Backend, ProbeResult, Duration)> = stream::iter(backends)
+        .filter(|backend| std::future::ready(backend.health_check_enabled))
+        .map(|backend| async move {
+            let start = Instant::now();
+            let client = clients.get(&backend.label);
+            let url = endpoints.url_for(backend);
+            let result = perform_health_check(&client, backend, &url, health_config).await;
+            (backend, result, start.elapsed())
+        })
+        .buffered(health_config.probe_concurrency)
+        .collect()
+        .await;
+
+    let label_width = backends
+        .iter()
+        .map(|b| b.label.len())
+        .max()
+        .unwrap_or(0)
+        .max("BACKEND".len());
+
+    tracing::info!("Startup probe results:");
+    tracing::info!(
+        "  {:<width$}  {:<6}  {:>10}  ERROR",
+        "BACKEND",
+        "STATUS",
+        "LATENCY",
+        width = label_width
+    );
+
+    // Every backend's slot is known before any is judged for lagging. No
+    // backend is known to be healthy yet, so all of them count this once.
+    let startup_slot = results
+        .iter()
+        .filter_map(|(_, result, _)| result.as_ref().ok().copied().flatten())
+        .max()
+        .unwrap_or(0);
+    health_state
+        .latest_slot
+        .store(startup_slot, Ordering::Relaxed);
+
+    // Backends with checks disabled are always healthy
+    let mut healthy_count = 0;
+    for backend in backends.iter().filter(|b| !b.health_check_enabled) {
+        healthy_count += 1;
+        tracing::info!(
+            "  {:<width$}  {:<6}  {:>10}  checks disabled",
+            backend.label,
+            "-",
+            "-",
+            width = label_width
+        );
+    }
+    for (backend, result, latency) in results {
+        let mut status = BackendHealthStatus {
+            last_check_time: Some(SystemTime::now()),
+            last_slot: result.as_ref().ok().copied().flatten(),
+            ..Default::default()
+        };
+        let result = check_slot_lag(result, health_state, health_config);
+        match result {
+            Ok(_) => {
+                healthy_count += 1;
+                status.consecutive_successes = 1;
+            }
+            Err(ref error) => {
+                status.healthy = false;
+                status.consecutive_failures = 1;
+                status.last_error = Some(error.message.clone());
+                status.last_error_category = Some(error.category);
+            }
+        }
+
+        tracing::info!(
+            "  {:<width$}  {:<6}  {:>8}ms  {}",
+            backend.label,
+            if result.is_ok() { "ok" } else { "FAIL" },
+            latency.as_millis(),
+            result
+                .as_ref()
+                .err()
+                .map(|e| format!("[{:?}] {}", e.category, e.message))
+                .unwrap_or_else(|| "-".to_string()),
+            width = label_width
+        );
+
+        health_state.update_status(&backend.label, status);
+    }
+
+    healthy_count
+}
+
+/// Keep connection pools to healthy backends warm by sending the health check
+/// request on a fixed interval. Results don't affect health status.
+pub async fn keepalive_warm_loop(
+    clients: Arc<BackendClients>,
+    endpoints: Arc<SrvEndpoints>,
+    routing: Arc<ArcSwap<Routing>>,
+    health_state: Arc<HealthState>,
+    health_config: HealthCheckConfig,
+    interval: Duration,
+) {
+    loop {
+        sleep(interval).await;
+
+        let routing = routing.load_full();
+        let healthy = routing.backends.iter().filter(|backend| {
+            backend.health_check_enabled
+                && health_state
+                    .get_status(&backend.label)
+                    .map(|status| status.healthy)
+                    .unwrap_or(false)
+        });
+        stream::iter(healthy)
+            .for_each_concurrent(health_config.probe_concurrency, |backend| {
+                let client = clients.get(&backend.label);
+                let url = endpoints.url_for(backend);
+                let health_config = &health_config;
+                async move {
+                    if let Err(e) =
+                        perform_health_check(&client, backend, &url, health_config).await
+                    {
+                        tracing::debug!(
+                            "Keepalive warm request to {} failed: {}",
+                            backend.label,
+                            e
+                        );
+                    }
+                }
+            })
+            .await;
+    }
+}
+
+/// Delay before a backend's next probe. Without `backoff_max_secs` every
+/// backend is probed each `interval_secs`. With it, a backend that has started
+/// failing, or an unhealthy one that has started passing, is rechecked after
+/// `FAST_RECHECK`, and an unhealthy one is probed at an interval that doubles
+/// with each further failure, up to `backoff_max_secs`.
+fn probe_delay(
+    status: &BackendHealthStatus,
+    failures_threshold: u32,
+    health_config: &HealthCheckConfig,
+) -> Duration {
+    let interval = Duration::from_secs(health_config.interval_secs);
+    let Some(max_secs) = health_config.backoff_max_secs else {
+        return interval;
+    };
+    let changing = if status.healthy {
+        status.consecutive_failures > 0
+    } else {
+        status.consecutive_successes > 0
+    };
+    if changing {
+        return FAST_RECHECK.min(interval);
+    }
+    if status.healthy {
+        return interval;
+    }
+    let doublings = status
+        .consecutive_failures
+        .saturating_sub(failures_threshold)
+        .min(16);
+    (interval * 2u32.pow(doublings)).min(Duration::from_secs(max_secs))
+}
+
 pub async fn health_check_loop(
-    client: Client<HttpsConnector<HttpConnector>, Body>,
-    backends: Vec<Backend>,
+    clients: Arc<BackendClients>,
+    endpoints: Arc<SrvEndpoints>,
+    routing: Arc<ArcSwap<Routing>>,
     health_state: Arc<HealthState>,
     health_config: HealthCheckConfig,
 ) {
-    let check_interval = Duration::from_secs(health_config.interval_secs);
+    // When each backend is next due; backends not listed are due now
+    let mut next_probe: HashMap<String, Instant> = HashMap::new();
+    let mut delays: Vec<(String, Duration)> = Vec::new();
 
     loop {
-        for backend in &backends {
-            let check_result = perform_health_check(&client, backend, &health_config).await;
+        // Backends are re-read every round so reloaded ones get checked
+        let routing = routing.load_full();
+        let round_start = Instant::now();
+        for backend in &routing.backends {
+            if !backend.health_check_enabled
+                || next_probe
+                    .get(&backend.label)
+                    .is_some_and(|due| *due > round_start)
+            {
+                continue;
+            }
+            let client = clients.get(&backend.label);
+            let url = endpoints.url_for(backend);
+            let probe = perform_health_check(&client, backend, &url, &health_config).await;
 
             // Get current status
             let mut current_status = health_state.get_status(&backend.label).unwrap_or_default();
+            if let Ok(Some(slot)) = probe {
+                current_status.last_slot = Some(slot);
+            }
+            let check_result = check_slot_lag(probe, &health_state, &health_config);
 
             let previous_healthy = current_status.healthy;
 
+            // A restored status is only a hint: the first fresh probe decides
+            let was_restored = current_status.restored;
+            current_status.restored = false;
+
             match check_result {
                 Ok(_) => {
                     current_status.consecutive_successes += 1;
                     current_status.consecutive_failures = 0;
                     current_status.last_error = None;
+                    current_status.last_error_category = None;
 
                     // Mark healthy if threshold reached
-                    if current_status.consecutive_successes
-                        >= health_config.consecutive_successes_threshold
+                    if was_restored
+                        || current_status.consecutive_successes
+                            >= backend.successes_threshold(&health_config)
                     {
                         current_status.healthy = true;
                     }
@@ -150,11 +812,13 @@ pub async fn health_check_loop(
                 Err(error) => {
                     current_status.consecutive_failures += 1;
                     current_status.consecutive_successes = 0;
-                    current_status.last_error = Some(error.clone());
+                    current_status.last_error = Some(error.message.clone());
+                    current_status.last_error_category = Some(error.category);
 
                     // Mark unhealthy if threshold reached
-                    if current_status.consecutive_failures
-                        >= health_config.consecutive_failures_threshold
+                    if was_restored
+                        || current_status.consecutive_failures
+                            >= backend.failures_threshold(&health_config)
                     {
                         current_status.healthy = false;
                     }
@@ -177,6 +841,8 @@ pub async fn health_check_loop(
                     backend.label,
                     current_status.consecutive_failures
                 );
+                // Don't let new requests reuse connections to a known-bad backend
+                clients.evict(&backend.label);
             } else if !previous_healthy && current_status.healthy {
                 tracing::info!(
                     "Backend {} marked as HEALTHY after {} consecutive successes",
@@ -185,10 +851,39 @@ pub async fn health_check_loop(
                 );
             }
 
+            let delay = probe_delay(
+                &current_status,
+                backend.failures_threshold(&health_config),
+                &health_config,
+            );
+            delays.push((backend.label.clone(), delay));
+
             // Update state
             health_state.update_status(&backend.label, current_status);
         }
 
-        sleep(check_interval).await;
+        // Lag is judged against the previous round's healthy backends
+        health_state.refresh_latest_slot();
+
+        if !delays.is_empty() {
+            if let Some(ref path) = health_config.state_file {
+                if let Err(e) = health_state.save_to_file(path) {
+                    tracing::warn!("Failed to persist health state to {}: {}", path, e);
+                }
+            }
+        }
+
+        // Schedule from the end of the round, so slow probes never overlap
+        let round_end = Instant::now();
+        for (label, delay) in delays.drain(..) {
+            next_probe.insert(label, round_end + delay);
+        }
+        next_probe.retain(|label, _| routing.backends.iter().any(|b| &b.label == label));
+        let wake = next_probe
+            .values()
+            .min()
+            .copied()
+            .unwrap_or(round_end + Duration::from_secs(health_config.interval_secs));
+        sleep_until(wake.into()).await;
     }
 }
@@ -1,23 +1,62 @@
+mod access_log;
+mod auth;
+mod cache;
+mod client;
 mod config;
+mod dead_letter;
+mod dedup;
+mod discovery;
 mod handlers;
 mod health;
+mod metrics;
+mod rate_limit;
 mod state;
 
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{future::IntoFuture, net::SocketAddr, path::Path, sync::Arc};
 
+use arc_swap::ArcSwap;
 use axum::{
+    http::{header, HeaderName, Method},
     middleware,
     routing::{get, post},
     Router,
 };
+use axum_server::{accept::NoDelayAcceptor, tls_rustls::RustlsConfig, Handle};
 use clap::Parser;
-use config::load_config;
-use handlers::{extract_rpc_method, health_endpoint, log_requests, proxy, ws_proxy};
-use health::{health_check_loop, HealthState};
-use hyper_tls::HttpsConnector;
-use hyper_util::client::legacy::Client;
-use state::AppState;
-use tracing::info;
+use client::{BackendClients, ClientOptions};
+use config::{load_config, Config, LogFormat, Strategy};
+use dead_letter::DeadLetterLog;
+use discovery::{srv_refresh_loop, SrvEndpoints};
+use futures_util::future::BoxFuture;
+use handlers::{
+    admin_api_keys, admin_drain_backend, admin_methods, admin_recent, admin_reset_breaker,
+    admin_stats, admin_undrain_backend, assign_request_id, enforce_total_timeout,
+    extract_rpc_method, get_proxy, health_endpoint, livez, log_requests, metrics_endpoint, proxy,
+    readyz, ws_proxy,
+};
+use health::{health_check_loop, keepalive_warm_loop, startup_probe, HealthState};
+use hickory_resolver::TokioAsyncResolver;
+use metrics::{install_prometheus, prometheus_upkeep_loop};
+use state::{AppState, Routing};
+use tokio::{
+    net::{TcpListener, TcpSocket},
+    sync::Notify,
+    time::Duration,
+};
+use tower_http::{
+    compression::{predicate::SizeAbove, CompressionLayer},
+    cors::{AllowOrigin, CorsLayer},
+};
+use tracing::{error, info, warn};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    filter::LevelFilter,
+    fmt::writer::{BoxMakeWriter, MakeWriterExt},
+    EnvFilter,
+};
 
 #[derive(Parser, Debug)]
 #[command(name = "rpc-router")]
@@ -26,26 +65,201 @@ struct Args {
     /// Path to configuration file
     #[arg(short, long, default_value = "config.toml")]
     config: String,
+    /// Validate the configuration, print a summary and exit without serving
+    #[arg(long, visible_alias = "validate")]
+    check: bool,
+}
+
+/// Each backend with the share of weighted traffic it will receive, so the
+/// effective split is obvious regardless of how weights are scaled
+fn backend_summary(config: &Config) -> Vec<String> {
+    let total_weight: u32 = config.backends.iter().map(|b| b.weight).sum();
+    let divisor = config.backends.iter().map(|b| b.weight).fold(0, gcd).max(1);
+    let ratio: Vec<String> = config
+        .backends
+        .iter()
+        .map(|b| (b.weight / divisor).to_string())
+        .collect();
+
+    let mut lines = vec![format!(
+        "Loaded {} backends (total weight: {}, normalized ratio {})",
+        config.backends.len(),
+        total_weight,
+        ratio.join(":")
+    )];
+    for backend in &config.backends {
+        let share = backend.weight as f64 * 100.0 / total_weight as f64;
+        // Tiers don't share traffic, so the split by weight would mislead
+        let mut details = if config.proxy.strategy == Strategy::Priority {
+            format!("weight: {}, priority {}", backend.weight, backend.priority)
+        } else {
+            format!("weight: {}, {:.1}% of traffic", backend.weight, share)
+        };
+        if let Some(max_rps) = backend.max_rps {
+            details.push_str(&format!(", capped at {} rps", max_rps));
+            if let Some(reserved) = backend.reserved_rps(&config.proxy) {
+                details.push_str(&format!(", spilling over above {:.1} rps", reserved));
+            }
+        }
+        lines.push(format!(
+            "  - [{}] {} ({})",
+            backend.label, backend.url, details
+        ));
+    }
+    lines
+}
+
+fn log_backends(config: &Config) {
+    for line in backend_summary(config) {
+        info!("{}", line);
+    }
+}
+
+/// `--check`: load and validate the config, print what it would run and exit.
+/// Nothing is bound and no health checks are sent.
+fn check_config(path: &str) -> ! {
+    let config = match load_config(path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Configuration {} is valid", path);
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    println!(
+        "Would listen on {}://{}",
+        scheme,
+        SocketAddr::new(config.bind_addr, config.port)
+    );
+    for line in backend_summary(&config) {
+        println!("{}", line);
+    }
+    if !config.method_routes.is_empty() {
+        println!("Method routing overrides:");
+        let mut routes: Vec<_> = config.method_routes.iter().collect();
+        routes.sort();
+        for (method, label) in routes {
+            println!("  - {} -> {}", method, label);
+        }
+    }
+    println!(
+        "{} API keys, strategy {:?}, max_retries {}",
+        config.api_keys.len(),
+        config.proxy.strategy,
+        config.proxy.max_retries
+    );
+    std::process::exit(0);
+}
+
+/// CORS for browser clients, or None when no origins are allowed. Preflight
+/// requests are answered by the layer without reaching the proxy.
+fn cors_layer(allowed_origins: &[String]) -> Option<CorsLayer> {
+    let allow_origin = match allowed_origins {
+        [] => return None,
+        [any] if any == "*" => AllowOrigin::any(),
+        origins => AllowOrigin::list(origins.iter().map(|origin| origin.parse().unwrap())),
+    };
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION])
+            .expose_headers([HeaderName::from_static("x-request-id")]),
+    )
+}
+
+/// Bind the listening socket with a configurable accept backlog
+fn bind_listener(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv6() {
+        TcpSocket::new_v6()?
+    } else {
+        TcpSocket::new_v4()?
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Log to stdout, or to a daily-rotated `log_file` (and stdout too with
+/// `log_also_stdout`). The returned guard flushes the file writer on drop.
+fn init_logging(config: &Config) -> Option<WorkerGuard> {
+    let (writer, guard) = match config.log_file {
+        Some(ref path) => {
+            let path = Path::new(path);
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix(path.file_name().unwrap_or_default().to_string_lossy())
+                .build(path.parent().unwrap_or(Path::new("")))
+                .expect("Failed to open log_file");
+            let (file, guard) = tracing_appender::non_blocking(appender);
+            let writer = if config.log_also_stdout {
+                BoxMakeWriter::new(file.and(std::io::stdout))
+            } else {
+                BoxMakeWriter::new(file)
+            };
+            (writer, Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
 
+    // RUST_LOG overrides the default, e.g. to enable the debug-level
+    // `body_sample` target
+    let filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(config.log_file.is_none());
+    match config.log_format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+    guard
+}
+
+fn main() {
     // Parse command-line arguments
     let args = Args::parse();
+    if args.check {
+        check_config(&args.config);
+    }
 
     // Load configuration from TOML file
     let config = load_config(&args.config).expect("Failed to load router configuration");
 
+    // Flushes buffered log lines on drop, so it must live until exit
+    let _log_guard = init_logging(&config);
+
     info!("Loaded configuration from: {}", args.config);
-    info!("Loaded {} backends", config.backends.len());
-    for backend in &config.backends {
-        info!(
-            "  - [{}] {} (weight: {})",
-            backend.label, backend.url, backend.weight
-        );
+
+    // Build the runtime explicitly so the worker thread count is configurable
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = config.server.worker_threads {
+        runtime.worker_threads(threads);
     }
+    runtime
+        .build()
+        .expect("Failed to build Tokio runtime")
+        .block_on(run(config, args.config));
+}
+
+async fn run(config: Config, config_path: String) {
+    log_backends(&config);
 
     if !config.method_routes.is_empty() {
         info!("Method routing overrides:");
@@ -54,33 +268,113 @@ async fn main() {
         }
     }
 
-    // Build label-to-URL mapping
-    let label_to_url: HashMap<String, String> = config
-        .backends
-        .iter()
-        .map(|b| (b.label.clone(), b.url.clone()))
-        .collect();
+    // Backends, API keys and method routes are swapped in on reload
+    let routing = Arc::new(ArcSwap::from_pointee(Routing::new(&config)));
 
     // Initialize health state
     let backend_labels: Vec<String> = config.backends.iter().map(|b| b.label.clone()).collect();
-    let health_state = Arc::new(HealthState::new(backend_labels));
-
-    let https = HttpsConnector::new();
-    let client = Client::builder(hyper_util::rt::TokioExecutor::new()).build(https);
-
-    let state = Arc::new(AppState {
-        client: client.clone(),
-        backends: config.backends.clone(),
-        api_keys: config.api_keys,
-        method_routes: config.method_routes,
-        label_to_url,
-        health_state: health_state.clone(),
-        proxy_timeout_secs: config.proxy.timeout_secs,
+    let health_state = Arc::new(HealthState::new(backend_labels.clone()));
+    if let Some(ref path) = config.health_check.state_file {
+        match health_state.load_from_file(path) {
+            Ok(restored) => info!(
+                "Restored health state for {} backends from {}",
+                restored, path
+            ),
+            Err(e) => info!("No health state restored from {}: {}", path, e),
+        }
+    }
+    health_state.set_unchecked(&config.backends);
+
+    let dead_letter = config.proxy.dead_letter_path.as_ref().map(|path| {
+        info!(
+            "Writing undeliverable requests to dead-letter log: {}",
+            path
+        );
+        DeadLetterLog::open(path).expect("Failed to open dead-letter log")
     });
 
+    let client_options = ClientOptions {
+        nodelay: config.proxy.tcp_nodelay,
+        keepalive: (config.proxy.tcp_keepalive_secs > 0)
+            .then(|| Duration::from_secs(config.proxy.tcp_keepalive_secs)),
+        pool_max_idle_per_host: config.proxy.pool_max_idle_per_host,
+        pool_idle_timeout: Duration::from_secs(config.proxy.pool_idle_timeout_secs),
+        http2_only: config.proxy.http2_only,
+    };
+    let clients = Arc::new(BackendClients::new(&config, client_options));
+
+    // Resolve SRV backends before serving, then keep them fresh
+    let srv_endpoints = Arc::new(SrvEndpoints::new());
+    if config.backends.iter().any(|b| b.srv.is_some()) {
+        let resolver = TokioAsyncResolver::tokio_from_system_conf()
+            .expect("Failed to load system DNS configuration");
+        srv_endpoints
+            .refresh(&resolver, &routing.load().backends)
+            .await;
+        tokio::spawn(srv_refresh_loop(
+            resolver,
+            routing.clone(),
+            srv_endpoints.clone(),
+            Duration::from_secs(config.proxy.srv_refresh_secs),
+        ));
+    }
+
+    // Installed before the metrics below, which register their series with it
+    let prometheus = config.proxy.metrics_enabled.then(|| {
+        let handle = install_prometheus().expect("Failed to install the Prometheus recorder");
+        tokio::spawn(prometheus_upkeep_loop(handle.clone()));
+        handle
+    });
+
+    let state = Arc::new(AppState::new(
+        &config,
+        routing.clone(),
+        health_state.clone(),
+        clients.clone(),
+        srv_endpoints.clone(),
+        dead_letter,
+        prometheus,
+    ));
+
+    if config.health_check.probe_before_listen {
+        let healthy = startup_probe(
+            &clients,
+            &srv_endpoints,
+            &config.backends,
+            &health_state,
+            &config.health_check,
+        )
+        .await;
+        if healthy < config.health_check.min_healthy {
+            error!(
+                "Startup probe failed: {}/{} backends healthy, {} required",
+                healthy,
+                config.backends.len(),
+                config.health_check.min_healthy
+            );
+            std::process::exit(1);
+        }
+        info!(
+            "Startup probe passed: {}/{} backends healthy",
+            healthy,
+            config.backends.len()
+        );
+    }
+
+    if let Some(secs) = config.proxy.keepalive_warm_interval_secs {
+        info!("Keeping backend connections warm (interval: {}s)", secs);
+        tokio::spawn(keepalive_warm_loop(
+            clients.clone(),
+            srv_endpoints.clone(),
+            routing.clone(),
+            health_state.clone(),
+            config.health_check.clone(),
+            Duration::from_secs(secs),
+        ));
+    }
+
     // Spawn background health check task
-    let health_check_client = client.clone();
-    let health_check_backends = config.backends.clone();
+    let health_check_clients = clients.clone();
     let health_check_config = config.health_check.clone();
 
     tokio::spawn(async move {
@@ -91,31 +385,221 @@ async fn main() {
             health_check_config.method
         );
         health_check_loop(
-            health_check_client,
-            health_check_backends,
+            health_check_clients,
+            srv_endpoints,
+            routing,
             health_state,
             health_check_config,
         )
         .await;
     });
 
-    let app = Router::new()
-        .route("/", post(proxy))
-        .route("/", get(ws_proxy))
-        .route("/*path", post(proxy))
+    // Certificates are reloaded in place on SIGHUP
+    let tls = match config.tls {
+        Some(ref tls) => Some(
+            RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .expect("Failed to load TLS certificate"),
+        ),
+        None => None,
+    };
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone(), config_path, tls.clone()));
+
+    let mut app = Router::new()
+        .route("/", post(proxy).get(ws_proxy))
+        .route("/ws", get(ws_proxy))
+        .route("/*path", post(proxy).get(get_proxy))
         .route("/health", get(health_endpoint))
-        .with_state(state)
-        .layer(middleware::from_fn(log_requests))
-        .layer(middleware::from_fn(extract_rpc_method));
+        .route("/livez", get(livez))
+        .route("/readyz", get(readyz))
+        .route("/admin/api-keys", get(admin_api_keys))
+        .route("/admin/methods", get(admin_methods))
+        .route("/admin/recent", get(admin_recent))
+        .route("/admin/stats", get(admin_stats))
+        .route(
+            "/admin/backends/:label/reset-breaker",
+            post(admin_reset_breaker),
+        )
+        .route("/admin/backends/:label/drain", post(admin_drain_backend))
+        .route(
+            "/admin/backends/:label/undrain",
+            post(admin_undrain_backend),
+        );
+    if config.proxy.metrics_enabled {
+        app = app.route("/metrics", get(metrics_endpoint));
+    }
+    if config.proxy.compress_responses {
+        app = app.layer(
+            CompressionLayer::new().compress_when(SizeAbove::new(config.proxy.compress_min_bytes)),
+        );
+    }
+    let shutdown_state = state.clone();
+    let app = app
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.clone(), log_requests))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            extract_rpc_method,
+        ))
+        .layer(middleware::from_fn_with_state(state, enforce_total_timeout))
+        .layer(middleware::from_fn(assign_request_id));
+    let app = match cors_layer(&config.proxy.cors_allowed_origins) {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    info!("Listening on http://{}", addr);
-    info!("Health monitoring endpoint: http://{}/health", addr);
+    let addr = SocketAddr::new(config.bind_addr, config.port);
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("Listening on {}://{}", scheme, addr);
+    info!("Health monitoring endpoint: {}://{}/health", scheme, addr);
 
-    axum::serve(
-        tokio::net::TcpListener::bind(addr).await.unwrap(),
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
+    let listener = bind_listener(addr, config.server.tcp_backlog).expect("Failed to bind listener");
+    let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let shutdown_started = Arc::new(Notify::new());
+    let graceful_shutdown = {
+        let shutdown_started = shutdown_started.clone();
+        let shutdown_state = shutdown_state.clone();
+        async move {
+            shutdown_signal().await;
+            info!(
+                "Shutting down: no longer accepting connections, {} requests in flight",
+                shutdown_state.in_flight.total()
+            );
+            // Keep the statuses seen since the last probe round
+            if let Some(ref path) = shutdown_state.health_check.state_file {
+                match shutdown_state.health_state.save_to_file(path) {
+                    Ok(()) => info!("Saved health state to {}", path),
+                    Err(e) => warn!("Failed to persist health state to {}: {}", path, e),
+                }
+            }
+            shutdown_started.notify_one();
+        }
+    };
+    let server: BoxFuture<'static, std::io::Result<()>> = match tls {
+        None => Box::pin(
+            axum::serve(listener, make_service)
+                .tcp_nodelay(config.proxy.tcp_nodelay)
+                .with_graceful_shutdown(graceful_shutdown)
+                .into_future(),
+        ),
+        Some(tls) => {
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    graceful_shutdown.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            let server = axum_server::from_tcp_rustls(
+                listener.into_std().expect("Failed to convert listener"),
+                tls,
+            )
+            .handle(handle);
+            if config.proxy.tcp_nodelay {
+                Box::pin(
+                    server
+                        .map(|acceptor| acceptor.acceptor(NoDelayAcceptor))
+                        .serve(make_service),
+                )
+            } else {
+                Box::pin(server.serve(make_service))
+            }
+        }
+    };
+
+    // Stop waiting for open connections once the grace period has elapsed
+    let shutdown_timeout = Duration::from_secs(config.proxy.shutdown_timeout_secs);
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = async {
+            shutdown_started.notified().await;
+            tokio::time::sleep(shutdown_timeout).await;
+        } => {
+            warn!(
+                "Shutdown grace period of {}s elapsed with {} requests in flight, exiting",
+                shutdown_timeout.as_secs(),
+                shutdown_state.in_flight.total()
+            );
+            return;
+        }
+    }
+    info!("All connections drained, exiting");
+}
+
+/// Reload the configuration on SIGHUP. Backends and their weights, API keys
+/// and method routes are swapped in atomically; a config that fails to load
+/// or validate is rejected and the current one kept. Backends no longer listed
+/// drain over `drain_grace_secs` before they are removed. When serving HTTPS,
+/// the certificate and key are re-read too.
+#[cfg(unix)]
+async fn reload_on_sighup(state: Arc<AppState>, config_path: String, tls: Option<RustlsConfig>) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("Failed to install SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        info!(
+            "Received SIGHUP, reloading configuration from {}",
+            config_path
+        );
+        let config = match load_config(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Config reload failed, keeping current configuration: {}", e);
+                continue;
+            }
+        };
+        log_backends(&config);
+        for label in state.reload(&config) {
+            let state = state.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(state.drains.grace()).await;
+                state.finish_removal(&label);
+            });
+        }
+        if let Some(ref rustls) = tls {
+            match config.tls {
+                Some(ref paths) => {
+                    match rustls
+                        .reload_from_pem_file(&paths.cert_path, &paths.key_path)
+                        .await
+                    {
+                        Ok(()) => info!("TLS certificate reloaded from {}", paths.cert_path),
+                        Err(e) => error!(
+                            "TLS certificate reload failed, keeping current certificate: {}",
+                            e
+                        ),
+                    }
+                }
+                None => warn!("Switching from HTTPS to HTTP requires a restart"),
+            }
+        } else if config.tls.is_some() {
+            warn!("Switching from HTTP to HTTPS requires a restart");
+        }
+        info!("Configuration reloaded");
+    }
+}
+
+/// Resolves on SIGTERM or Ctrl-C (SIGINT)
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl-C handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
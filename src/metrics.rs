@@ -0,0 +1,396 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ::metrics::{
+    counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Unit,
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::health::BackendError;
+
+const REQUESTS: &str = "rpc_router_requests_total";
+const REQUEST_DURATION: &str = "rpc_router_request_duration_seconds";
+const BACKEND_REQUESTS: &str = "rpc_router_backend_requests_total";
+const BACKEND_ERRORS: &str = "rpc_router_backend_errors_total";
+const BACKEND_FAILURES: &str = "rpc_router_backend_failures_total";
+const API_KEY_REQUESTS: &str = "rpc_router_api_key_requests_total";
+const API_KEY_ERRORS: &str = "rpc_router_api_key_errors_total";
+const METHOD_REQUESTS: &str = "rpc_router_method_requests_total";
+const BACKEND_RATE_TOKENS: &str = "rpc_router_backend_rate_tokens";
+
+/// Upper bounds (in seconds) of the request duration histogram buckets
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Install the global Prometheus recorder that `/metrics` renders. Until it
+/// is installed (or when metrics are disabled) the counters below are no-ops;
+/// the admin stats are kept either way.
+pub fn install_prometheus() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Full(REQUEST_DURATION.to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    describe_counter!(REQUESTS, "Requests served, by response status");
+    describe_histogram!(
+        REQUEST_DURATION,
+        Unit::Seconds,
+        "Time to serve a request's response headers"
+    );
+    describe_counter!(BACKEND_REQUESTS, "Upstream attempts per backend");
+    describe_counter!(BACKEND_ERRORS, "Failed upstream attempts per backend");
+    describe_counter!(
+        BACKEND_FAILURES,
+        "Failed upstream attempts per backend, by kind"
+    );
+    describe_counter!(API_KEY_REQUESTS, "Requests per API key (hashed)");
+    describe_counter!(API_KEY_ERRORS, "Error responses per API key (hashed)");
+    describe_counter!(METHOD_REQUESTS, "Requests per JSON-RPC method");
+    describe_gauge!(
+        BACKEND_RATE_TOKENS,
+        "Tokens left in each backend's max_rps bucket"
+    );
+    Ok(handle)
+}
+
+/// How often histogram samples are folded into the rendered buckets
+const PROMETHEUS_UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Run the recorder's upkeep, which drains histogram samples recorded
+/// between scrapes so they don't accumulate in memory
+pub async fn prometheus_upkeep_loop(handle: PrometheusHandle) {
+    loop {
+        tokio::time::sleep(PROMETHEUS_UPKEEP_INTERVAL).await;
+        handle.run_upkeep();
+    }
+}
+
+/// Record a served request in the request counters and duration histogram
+pub fn record_request(status: u16, duration: Duration) {
+    counter!(REQUESTS, "status" => status.to_string()).increment(1);
+    histogram!(REQUEST_DURATION).record(duration.as_secs_f64());
+}
+
+/// Set the tokens left in a backend's `max_rps` bucket. Buckets refill
+/// continuously, so this is set when `/metrics` is scraped.
+pub fn record_rate_tokens(label: &str, tokens: f64) {
+    gauge!(BACKEND_RATE_TOKENS, "backend" => label.to_string()).set(tokens);
+}
+
+/// Methods of the Solana JSON-RPC HTTP API, tracked individually by
+/// `MethodMetrics`
+const SOLANA_RPC_METHODS: &[&str] = &[
+    "getAccountInfo",
+    "getBalance",
+    "getBlock",
+    "getBlockCommitment",
+    "getBlockHeight",
+    "getBlockProduction",
+    "getBlockTime",
+    "getBlocks",
+    "getBlocksWithLimit",
+    "getClusterNodes",
+    "getEpochInfo",
+    "getEpochSchedule",
+    "getFeeForMessage",
+    "getFirstAvailableBlock",
+    "getGenesisHash",
+    "getHealth",
+    "getHighestSnapshotSlot",
+    "getIdentity",
+    "getInflationGovernor",
+    "getInflationRate",
+    "getInflationReward",
+    "getLargestAccounts",
+    "getLatestBlockhash",
+    "getLeaderSchedule",
+    "getMaxRetransmitSlot",
+    "getMaxShredInsertSlot",
+    "getMinimumBalanceForRentExemption",
+    "getMultipleAccounts",
+    "getProgramAccounts",
+    "getRecentPerformanceSamples",
+    "getRecentPrioritizationFees",
+    "getSignatureStatuses",
+    "getSignaturesForAddress",
+    "getSlot",
+    "getSlotLeader",
+    "getSlotLeaders",
+    "getStakeMinimumDelegation",
+    "getSupply",
+    "getTokenAccountBalance",
+    "getTokenAccountsByDelegate",
+    "getTokenAccountsByOwner",
+    "getTokenLargestAccounts",
+    "getTokenSupply",
+    "getTransaction",
+    "getTransactionCount",
+    "getVersion",
+    "getVoteAccounts",
+    "isBlockhashValid",
+    "minimumLedgerSlot",
+    "requestAirdrop",
+    "sendTransaction",
+    "simulateTransaction",
+];
+
+/// Label for methods that aren't tracked individually
+const OTHER_METHOD: &str = "other";
+
+/// Stable, non-reversible identifier for an API key, safe to use in metric
+/// labels and logs
+pub fn api_key_id(key: &str) -> String {
+    let digest = format!("{:x}", Sha256::digest(key.as_bytes()));
+    digest[..16].to_string()
+}
+
+#[derive(Default)]
+struct KeyUsage {
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyUsageSnapshot {
+    pub key_id: String,
+    pub requests: u64,
+    pub errors: u64,
+}
+
+/// Request and error counters per configured API key, keyed by `api_key_id`
+pub struct ApiKeyMetrics {
+    usage: RwLock<HashMap<String, KeyUsage>>,
+}
+
+impl ApiKeyMetrics {
+    pub fn new(key_ids: &[String]) -> Self {
+        let metrics = Self {
+            usage: RwLock::new(HashMap::new()),
+        };
+        metrics.register(key_ids);
+        metrics
+    }
+
+    /// Start counting keys added by a config reload
+    pub fn register(&self, key_ids: &[String]) {
+        let mut usage = self.usage.write().unwrap();
+        for key_id in key_ids {
+            usage.entry(key_id.clone()).or_default();
+            counter!(API_KEY_REQUESTS, "key_id" => key_id.clone()).increment(0);
+            counter!(API_KEY_ERRORS, "key_id" => key_id.clone()).increment(0);
+        }
+    }
+
+    pub fn record(&self, key_id: &str, is_error: bool) {
+        if let Some(usage) = self.usage.read().unwrap().get(key_id) {
+            usage.requests.fetch_add(1, Ordering::Relaxed);
+            counter!(API_KEY_REQUESTS, "key_id" => key_id.to_string()).increment(1);
+            if is_error {
+                usage.errors.fetch_add(1, Ordering::Relaxed);
+                counter!(API_KEY_ERRORS, "key_id" => key_id.to_string()).increment(1);
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<ApiKeyUsageSnapshot> {
+        let mut snapshot: Vec<ApiKeyUsageSnapshot> = self
+            .usage
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key_id, usage)| ApiKeyUsageSnapshot {
+                key_id: key_id.clone(),
+                requests: usage.requests.load(Ordering::Relaxed),
+                errors: usage.errors.load(Ordering::Relaxed),
+            })
+            .collect();
+        snapshot.sort_by_key(|usage| std::cmp::Reverse(usage.requests));
+        snapshot
+    }
+}
+
+/// Cumulative request, error and latency totals, with the time of last use
+#[derive(Default)]
+struct UsageCounters {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    latency_micros: AtomicU64,
+    latency_samples: AtomicU64,
+    /// Unix time in milliseconds of the last request, 0 if never used
+    last_used_ms: AtomicU64,
+}
+
+impl UsageCounters {
+    fn record(&self, success: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        self.last_used_ms.store(now_ms, Ordering::Relaxed);
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        self.latency_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.latency_samples.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> UsageStats {
+        let samples = self.latency_samples.load(Ordering::Relaxed);
+        let last_used_ms = self.last_used_ms.load(Ordering::Relaxed);
+        UsageStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            avg_latency_ms: (samples > 0).then(|| {
+                self.latency_micros.load(Ordering::Relaxed) as f64 / samples as f64 / 1000.0
+            }),
+            last_used_ms: (last_used_ms > 0).then_some(last_used_ms),
+        }
+    }
+}
+
+/// Totals reported by `/admin/stats` for one backend or method
+#[derive(Serialize)]
+pub struct UsageStats {
+    pub requests: u64,
+    pub errors: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avg_latency_ms: Option<f64>,
+    /// Unix time in milliseconds of the last request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_ms: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct MethodCount {
+    pub method: String,
+    pub requests: u64,
+}
+
+/// Request counters per JSON-RPC method. Only Solana RPC methods and methods
+/// named in the config get their own counter; any other name is counted as
+/// `other`, so arbitrary client-supplied method names can't grow the label set.
+pub struct MethodMetrics {
+    counts: HashMap<String, UsageCounters>,
+}
+
+impl MethodMetrics {
+    pub fn new<'a>(configured_methods: impl IntoIterator<Item = &'a String>) -> Self {
+        let counts = SOLANA_RPC_METHODS
+            .iter()
+            .copied()
+            .chain(configured_methods.into_iter().map(String::as_str))
+            .chain([OTHER_METHOD])
+            .map(|method| (method.to_string(), UsageCounters::default()))
+            .collect();
+        Self { counts }
+    }
+
+    /// Record a finished request and how long the router took to answer it
+    pub fn record(&self, rpc_method: &str, success: bool, duration: Duration) {
+        let (method, counters) = self
+            .counts
+            .get_key_value(rpc_method)
+            .or_else(|| self.counts.get_key_value(OTHER_METHOD))
+            .expect("the other method is always counted");
+        counters.record(success);
+        counters.record_latency(duration);
+        counter!(METHOD_REQUESTS, "method" => method.clone()).increment(1);
+    }
+
+    /// Totals of methods with at least one request
+    pub fn stats(&self) -> BTreeMap<String, UsageStats> {
+        self.counts
+            .iter()
+            .map(|(method, counters)| (method.clone(), counters.stats()))
+            .filter(|(_, stats)| stats.requests > 0)
+            .collect()
+    }
+
+    /// Methods with at least one request, busiest first
+    pub fn top(&self, limit: usize) -> Vec<MethodCount> {
+        let mut counts: Vec<MethodCount> = self
+            .counts
+            .iter()
+            .map(|(method, count)| MethodCount {
+                method: method.clone(),
+                requests: count.requests.load(Ordering::Relaxed),
+            })
+            .filter(|count| count.requests > 0)
+            .collect();
+        counts.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.method.cmp(&b.method)));
+        counts.truncate(limit);
+        counts
+    }
+}
+
+/// Upstream attempts and failed attempts per backend
+pub struct BackendMetrics {
+    counters: RwLock<HashMap<String, UsageCounters>>,
+}
+
+impl BackendMetrics {
+    pub fn new(backend_labels: &[String]) -> Self {
+        let metrics = Self {
+            counters: RwLock::new(HashMap::new()),
+        };
+        metrics.register(backend_labels);
+        metrics
+    }
+
+    /// Start counting backends added by a config reload
+    pub fn register(&self, backend_labels: &[String]) {
+        let mut counters = self.counters.write().unwrap();
+        for label in backend_labels {
+            counters.entry(label.clone()).or_default();
+            counter!(BACKEND_REQUESTS, "backend" => label.clone()).increment(0);
+            counter!(BACKEND_ERRORS, "backend" => label.clone()).increment(0);
+            for kind in BackendError::KINDS {
+                counter!(BACKEND_FAILURES, "backend" => label.clone(), "kind" => kind).increment(0);
+            }
+        }
+    }
+
+    pub fn record(&self, label: &str, error: Option<&BackendError>) {
+        if let Some(counters) = self.counters.read().unwrap().get(label) {
+            counters.record(error.is_none());
+            counter!(BACKEND_REQUESTS, "backend" => label.to_string()).increment(1);
+            if let Some(error) = error {
+                counter!(BACKEND_ERRORS, "backend" => label.to_string()).increment(1);
+                let kind = BackendError::KINDS[error.index()];
+                counter!(BACKEND_FAILURES, "backend" => label.to_string(), "kind" => kind)
+                    .increment(1);
+            }
+        }
+    }
+
+    /// Record the time an attempt took to get response headers
+    pub fn record_latency(&self, label: &str, latency: Duration) {
+        if let Some(counters) = self.counters.read().unwrap().get(label) {
+            counters.record_latency(latency);
+        }
+    }
+
+    pub fn stats(&self) -> BTreeMap<String, UsageStats> {
+        self.counters
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(label, counters)| (label.clone(), counters.stats()))
+            .collect()
+    }
+}
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use lru::LruCache;
+use tokio::time::Duration;
+
+use crate::config::MethodRateLimit;
+
+/// Token bucket refilled continuously at `rate` tokens per second, holding at
+/// most `capacity` tokens.
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> Self {
+        Self::with_rate(rate as f64)
+    }
+
+    /// A bucket refilling at a fractional rate, holding at least one token
+    pub fn with_rate(rate: f64) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            capacity,
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+        state.last_refill = now;
+    }
+
+    /// Number of whole-or-fractional tokens currently available
+    pub fn available(&self) -> f64 {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        state.tokens
+    }
+
+    /// Whether at least one token is available, without consuming it
+    pub fn has_token(&self) -> bool {
+        self.available() >= 1.0
+    }
+
+    /// Consume a single token, returning false if the bucket is empty
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next token becomes available
+    pub fn retry_after(&self) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        if state.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - state.tokens) / self.rate)
+        }
+    }
+}
+
+/// A request rejected by a rate limiter
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimited {
+    /// Requests per second allowed by the limiter that rejected the request
+    pub limit: u32,
+    /// Time until the limiter's bucket has a token again
+    pub retry_after: Duration,
+}
+
+/// Token buckets for per-API-key rate limits, keyed by key id
+pub struct KeyRateLimiter {
+    buckets: HashMap<String, (u32, TokenBucket)>,
+}
+
+impl KeyRateLimiter {
+    pub fn new(limits: HashMap<String, u32>) -> Self {
+        Self {
+            buckets: limits
+                .into_iter()
+                .map(|(key_id, rps)| (key_id, (rps, TokenBucket::new(rps))))
+                .collect(),
+        }
+    }
+
+    /// Consume a token on behalf of `key_id`; keys without a limit always pass
+    pub fn check(&self, key_id: &str) -> Result<(), RateLimited> {
+        let Some((limit, bucket)) = self.buckets.get(key_id) else {
+            return Ok(());
+        };
+        if bucket.try_acquire() {
+            Ok(())
+        } else {
+            Err(RateLimited {
+                limit: *limit,
+                retry_after: bucket.retry_after(),
+            })
+        }
+    }
+}
+
+/// Number of clients tracked; past it the least recently seen is forgotten
+const IP_BUCKETS_CAPACITY: NonZeroUsize = match NonZeroUsize::new(100_000) {
+    Some(capacity) => capacity,
+    None => unreachable!(),
+};
+
+/// Token buckets for `proxy.ip_rate_limit`, one per client
+pub struct IpRateLimiter {
+    rps: u32,
+    buckets: Mutex<LruCache<IpAddr, TokenBucket>>,
+}
+
+impl IpRateLimiter {
+    pub fn new(rps: u32) -> Self {
+        Self {
+            rps,
+            buckets: Mutex::new(LruCache::new(IP_BUCKETS_CAPACITY)),
+        }
+    }
+
+    /// Consume a token on behalf of `ip`
+    pub fn check(&self, ip: IpAddr) -> Result<(), RateLimited> {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert(client_key(ip), || TokenBucket::new(self.rps));
+
+        if bucket.try_acquire() {
+            Ok(())
+        } else {
+            Err(RateLimited {
+                limit: self.rps,
+                retry_after: bucket.retry_after(),
+            })
+        }
+    }
+}
+
+/// The address a client is limited by: IPv4 addresses individually, IPv6
+/// by /64, since a single host is usually handed a whole /64
+fn client_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !0 << 64)),
+        v4 => v4,
+    }
+}
+
+/// Method name plus the API key id for per-key limits
+type MethodBucketKey = (String, Option<String>);
+
+/// Token buckets for `method_rate_limits`, one per method or, for `per_key`
+/// limits, one per (API key, method)
+pub struct MethodRateLimiter {
+    limits: HashMap<String, MethodRateLimit>,
+    buckets: Mutex<HashMap<MethodBucketKey, Arc<TokenBucket>>>,
+}
+
+impl MethodRateLimiter {
+    pub fn new(limits: HashMap<String, MethodRateLimit>) -> Self {
+        Self {
+            limits,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Consume a token for `method` on behalf of `key_id`
+    pub fn check(&self, method: &str, key_id: &str) -> Result<(), RateLimited> {
+        let Some(limit) = self.limits.get(method) else {
+            return Ok(());
+        };
+        let bucket_key = (
+            method.to_string(),
+            limit.per_key.then(|| key_id.to_string()),
+        );
+        let bucket = self
+            .buckets
+            .lock()
+            .unwrap()
+            .entry(bucket_key)
+            .or_insert_with(|| Arc::new(TokenBucket::new(limit.rps)))
+            .clone();
+
+        if bucket.try_acquire() {
+            Ok(())
+        } else {
+            Err(RateLimited {
+                limit: limit.rps,
+                retry_after: bucket.retry_after(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipv6_clients_share_a_bucket_per_64() {
+        let limiter = IpRateLimiter::new(1);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(limiter.check(ip("2001:db8:1:2::1")).is_ok());
+        assert!(limiter.check(ip("2001:db8:1:2:ffff::9")).is_err());
+        assert!(limiter.check(ip("2001:db8:1:3::1")).is_ok());
+    }
+
+    #[test]
+    fn ipv4_clients_are_limited_individually() {
+        let limiter = IpRateLimiter::new(1);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(limiter.check(ip("192.0.2.1")).is_ok());
+        assert!(limiter.check(ip("::ffff:192.0.2.1")).is_err());
+        assert!(limiter.check(ip("192.0.2.2")).is_ok());
+    }
+}
@@ -1,55 +1,1573 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Instant,
+};
 
-use axum::body::Body;
-use hyper_tls::HttpsConnector;
-use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use axum::http::HeaderName;
+use ipnet::IpNet;
+use metrics_exporter_prometheus::PrometheusHandle;
 use rand::Rng;
-use tracing::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::time::Duration;
+use tracing::{info, warn};
 
-use crate::{config::Backend, health::HealthState};
+use crate::{
+    access_log::{LogTemplate, RecentRequests},
+    auth::ApiKeys,
+    cache::ResponseCache,
+    client::BackendClients,
+    config::{
+        AdaptiveWeightConfig, Backend, Config, FailoverTripConfig, HealthCheckConfig, LogFormat,
+        RedirectPolicy, Strategy,
+    },
+    dead_letter::DeadLetterLog,
+    dedup::TransactionDedup,
+    discovery::SrvEndpoints,
+    health::{BackendError, HealthState},
+    metrics::{ApiKeyMetrics, BackendMetrics, MethodMetrics},
+    rate_limit::{IpRateLimiter, KeyRateLimiter, MethodRateLimiter, TokenBucket},
+};
 
+/// Remembers which backend served each client connection, keyed by the
+/// peer address (unique per open TCP connection). Entries expire after
+/// `idle` and are swept once per `idle` period, so closed connections don't
+/// accumulate.
+pub struct ConnectionAffinity {
+    idle: Duration,
+    pins: Mutex<Pins>,
+}
+
+struct Pins {
+    by_peer: HashMap<SocketAddr, (String, Instant)>,
+    swept_at: Instant,
+}
+
+impl ConnectionAffinity {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            pins: Mutex::new(Pins {
+                by_peer: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    fn get(&self, peer: &SocketAddr) -> Option<String> {
+        let pins = self.pins.lock().unwrap();
+        pins.by_peer
+            .get(peer)
+            .filter(|(_, last_used)| last_used.elapsed() < self.idle)
+            .map(|(label, _)| label.clone())
+    }
+
+    fn pin(&self, peer: SocketAddr, label: &str) {
+        let mut pins = self.pins.lock().unwrap();
+        if pins.swept_at.elapsed() >= self.idle {
+            pins.by_peer
+                .retain(|_, (_, last_used)| last_used.elapsed() < self.idle);
+            pins.swept_at = Instant::now();
+        }
+        pins.by_peer
+            .insert(peer, (label.to_string(), Instant::now()));
+    }
+}
+
+/// Tracks the failure rate of upstream attempts across all backends over a
+/// sliding window of one-second buckets. While the rate exceeds the
+/// configured threshold the trip is engaged and failover is disabled, so a
+/// total outage doesn't multiply load on every upstream.
+pub struct FailoverTrip {
+    config: FailoverTripConfig,
+    started: Instant,
+    // (second since start, successes, failures) per slot
+    buckets: Mutex<Vec<(u64, u64, u64)>>,
+    tripped: AtomicBool,
+}
+
+impl FailoverTrip {
+    pub fn new(config: FailoverTripConfig) -> Self {
+        let slots = config.window_secs as usize;
+        Self {
+            config,
+            started: Instant::now(),
+            buckets: Mutex::new(vec![(0, 0, 0); slots]),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record(&self, success: bool) {
+        let now = self.started.elapsed().as_secs();
+        let window = self.config.window_secs;
+        let (requests, failures) = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let slot = &mut buckets[(now % window) as usize];
+            if slot.0 != now {
+                *slot = (now, 0, 0);
+            }
+            if success {
+                slot.1 += 1;
+            } else {
+                slot.2 += 1;
+            }
+            buckets
+                .iter()
+                .filter(|(second, _, _)| now - second < window)
+                .fold((0, 0), |(requests, failures), (_, ok, failed)| {
+                    (requests + ok + failed, failures + failed)
+                })
+        };
+
+        let tripped = requests >= self.config.min_requests
+            && failures as f64 / requests as f64 > self.config.failure_rate;
+        if self.tripped.swap(tripped, Ordering::Relaxed) != tripped {
+            if tripped {
+                tracing::warn!(
+                    "Failover disabled: {}/{} upstream attempts failed in the last {}s",
+                    failures,
+                    requests,
+                    window
+                );
+            } else {
+                tracing::info!("Failover re-enabled: upstream failure rate recovered");
+            }
+        }
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+}
+
+/// Length of the buckets outcomes are counted in before being folded into
+/// the success rate, so the rate moves with time rather than request volume
+const SUCCESS_RATE_BUCKET: Duration = Duration::from_secs(1);
+
+/// Exponentially weighted moving average of each backend's success rate on
+/// real traffic, decaying with a configured half-life. Backend weights are
+/// scaled down once the error rate or average latency crosses its soft
+/// threshold, so a degrading backend sheds traffic gradually.
+pub struct SuccessRateWeights {
+    config: AdaptiveWeightConfig,
+    rates: Mutex<HashMap<String, SuccessRate>>,
+}
+
+struct SuccessRate {
+    rate: f64,
+    /// Outcomes counted since `bucket_start`, not yet in `rate`
+    successes: u32,
+    failures: u32,
+    bucket_start: Instant,
+}
+
+impl SuccessRateWeights {
+    pub fn new(config: AdaptiveWeightConfig) -> Self {
+        Self {
+            config,
+            rates: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, label: &str, success: bool) {
+        let now = Instant::now();
+        let mut rates = self.rates.lock().unwrap();
+        let entry = rates.entry(label.to_string()).or_insert(SuccessRate {
+            rate: 1.0,
+            successes: 0,
+            failures: 0,
+            bucket_start: now,
+        });
+        let elapsed = now.duration_since(entry.bucket_start);
+        if elapsed >= SUCCESS_RATE_BUCKET {
+            // Fold the finished bucket in, weighted by the time it covered
+            let total = entry.successes + entry.failures;
+            if total > 0 {
+                let sample = entry.successes as f64 / total as f64;
+                let decay = 0.5f64.powf(elapsed.as_secs_f64() / self.config.half_life_secs);
+                entry.rate = entry.rate * decay + sample * (1.0 - decay);
+            }
+            entry.successes = 0;
+            entry.failures = 0;
+            entry.bucket_start = now;
+        }
+        if success {
+            entry.successes += 1;
+        } else {
+            entry.failures += 1;
+        }
+    }
+
+    /// Multiplier (weight_floor..=1.0) applied to the backend's weight. Past
+    /// `error_rate_threshold` it falls linearly to 0 at a 100% error rate;
+    /// past `latency_threshold_ms` it is further scaled by threshold/latency.
+    pub fn factor(&self, label: &str, latency_ms: Option<f64>) -> f64 {
+        let rate = self
+            .rates
+            .lock()
+            .unwrap()
+            .get(label)
+            .map(|entry| entry.rate)
+            .unwrap_or(1.0);
+        let threshold = self.config.error_rate_threshold;
+        let excess_errors = (1.0 - rate - threshold).max(0.0);
+        let mut factor = 1.0 - excess_errors / (1.0 - threshold);
+        if let (Some(threshold), Some(latency)) = (self.config.latency_threshold_ms, latency_ms) {
+            if latency > threshold {
+                factor *= threshold / latency;
+            }
+        }
+        factor.max(self.config.weight_floor)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_started: Option<Instant>,
+}
+
+/// Per-backend circuit breakers driven by real traffic. After `threshold`
+/// consecutive failed attempts a backend's circuit opens and it is excluded
+/// from selection for `cooldown`; then a single trial request is let through
+/// (half-open), which closes the circuit on success or reopens it on failure.
+/// A trial that never reports back (e.g. the client disconnected) is given up
+/// after another `cooldown`, so the backend can't stay half-open forever.
+pub struct CircuitBreakers {
+    threshold: u32,
+    cooldown: Duration,
+    breakers: Mutex<HashMap<String, Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            breakers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn state_of(&self, breaker: &Breaker) -> BreakerState {
+        match breaker.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if opened_at.elapsed() < self.cooldown => BreakerState::Open,
+            Some(_) => BreakerState::HalfOpen,
+        }
+    }
+
+    fn trial_in_flight(&self, breaker: &Breaker) -> bool {
+        breaker
+            .trial_started
+            .is_some_and(|started| started.elapsed() < self.cooldown)
+    }
+
+    pub fn state(&self, label: &str) -> BreakerState {
+        self.breakers
+            .lock()
+            .unwrap()
+            .get(label)
+            .map(|breaker| self.state_of(breaker))
+            .unwrap_or(BreakerState::Closed)
+    }
+
+    /// Whether the backend may be selected: closed, or half-open with no
+    /// trial request in flight yet
+    pub fn allows(&self, label: &str) -> bool {
+        let breakers = self.breakers.lock().unwrap();
+        match breakers.get(label) {
+            Some(breaker) => match self.state_of(breaker) {
+                BreakerState::Closed => true,
+                BreakerState::Open => false,
+                BreakerState::HalfOpen => !self.trial_in_flight(breaker),
+            },
+            None => true,
+        }
+    }
+
+    /// Claim the half-open trial for a selected backend. Returns false if
+    /// another request already claimed it.
+    pub fn acquire(&self, label: &str) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let Some(breaker) = breakers.get_mut(label) else {
+            return true;
+        };
+        match self.state_of(breaker) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen if self.trial_in_flight(breaker) => false,
+            BreakerState::HalfOpen => {
+                breaker.trial_started = Some(Instant::now());
+                info!(
+                    "Circuit for backend {} half-open, sending trial request",
+                    label
+                );
+                true
+            }
+        }
+    }
+
+    /// Give up a half-open trial claimed by `acquire` whose request won't be
+    /// sent after all, so another request can claim it
+    pub fn release_trial(&self, label: &str) {
+        if let Some(breaker) = self.breakers.lock().unwrap().get_mut(label) {
+            breaker.trial_started = None;
+        }
+    }
+
+    pub fn record(&self, label: &str, success: bool) {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = breakers.entry(label.to_string()).or_default();
+        if success {
+            if breaker.opened_at.is_some() {
+                info!(
+                    "Circuit for backend {} closed after successful trial",
+                    label
+                );
+            }
+            *breaker = Breaker::default();
+            return;
+        }
+
+        breaker.consecutive_failures += 1;
+        if breaker.trial_started.is_some() {
+            tracing::warn!(
+                "Trial request to backend {} failed, circuit reopened",
+                label
+            );
+            breaker.opened_at = Some(Instant::now());
+            breaker.trial_started = None;
+        } else if breaker.opened_at.is_none() && breaker.consecutive_failures >= self.threshold {
+            tracing::warn!(
+                "Circuit for backend {} opened after {} consecutive failures",
+                label,
+                breaker.consecutive_failures
+            );
+            breaker.opened_at = Some(Instant::now());
+        }
+    }
+
+    /// Close the backend's circuit immediately. Returns the previous state.
+    pub fn reset(&self, label: &str) -> BreakerState {
+        let mut breakers = self.breakers.lock().unwrap();
+        let previous = breakers
+            .get(label)
+            .map(|breaker| self.state_of(breaker))
+            .unwrap_or(BreakerState::Closed);
+        breakers.remove(label);
+        previous
+    }
+}
+
+/// Decrements a backend's in-flight count when dropped
+pub struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Number of requests currently in flight to each backend
+pub struct InFlight {
+    counts: RwLock<HashMap<String, Arc<AtomicUsize>>>,
+}
+
+impl InFlight {
+    pub fn new(backend_labels: &[String]) -> Self {
+        let in_flight = Self {
+            counts: RwLock::new(HashMap::new()),
+        };
+        in_flight.register(backend_labels);
+        in_flight
+    }
+
+    /// Start tracking backends added by a config reload
+    pub fn register(&self, backend_labels: &[String]) {
+        let mut counts = self.counts.write().unwrap();
+        for label in backend_labels {
+            counts
+                .entry(label.clone())
+                .or_insert_with(|| Arc::new(AtomicUsize::new(0)));
+        }
+    }
+
+    pub fn count(&self, label: &str) -> usize {
+        self.counts
+            .read()
+            .unwrap()
+            .get(label)
+            .map(|count| count.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Requests in flight across all backends
+    pub fn total(&self) -> usize {
+        self.counts
+            .read()
+            .unwrap()
+            .values()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Count a request to `label` as in flight until the guard is dropped
+    pub fn track(&self, label: &str) -> Option<InFlightGuard> {
+        let count = self.counts.read().unwrap().get(label)?.clone();
+        count.fetch_add(1, Ordering::Relaxed);
+        Some(InFlightGuard(count))
+    }
+}
+
+/// Exponentially weighted moving average of each backend's response latency
+/// (time to response headers), in milliseconds
+pub struct LatencyEwma {
+    alpha: f64,
+    averages: Mutex<HashMap<String, f64>>,
+}
+
+impl LatencyEwma {
+    pub fn new(alpha: f64) -> Self {
+        Self {
+            alpha,
+            averages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record(&self, label: &str, latency: Duration) {
+        let sample = latency.as_secs_f64() * 1000.0;
+        let mut averages = self.averages.lock().unwrap();
+        averages
+            .entry(label.to_string())
+            .and_modify(|average| *average = self.alpha * sample + (1.0 - self.alpha) * *average)
+            .or_insert(sample);
+    }
+
+    pub fn get(&self, label: &str) -> Option<f64> {
+        self.averages.lock().unwrap().get(label).copied()
+    }
+
+    /// Latency used for selection. Backends without samples are assumed to be
+    /// as fast as the fastest known one, so they get probed.
+    fn estimate(&self, label: &str) -> f64 {
+        let averages = self.averages.lock().unwrap();
+        averages
+            .get(label)
+            .copied()
+            .or_else(|| averages.values().copied().reduce(f64::min))
+            .unwrap_or(1.0)
+            .max(0.1)
+    }
+}
+
+/// Smooth weighted round-robin (as in nginx). Each pick adds every
+/// candidate's weight to its running score, takes the highest score and
+/// subtracts the candidates' total weight from it, so weights 1/2/3 split
+/// every 6 requests exactly 1/2/3 and picks of the same backend are spread
+/// out. Memory and time per pick are linear in the number of backends,
+/// whatever the weights.
+pub struct RoundRobin {
+    scores: Mutex<HashMap<String, i64>>,
+}
+
+impl RoundRobin {
+    pub fn new<'a>(backends: impl IntoIterator<Item = &'a Backend>) -> Self {
+        Self {
+            scores: Mutex::new(backends.into_iter().map(|b| (b.label.clone(), 0)).collect()),
+        }
+    }
+
+    /// Next backend among `candidates`. Unavailable backends aren't
+    /// candidates, so their scores stay put while the others share their
+    /// traffic by weight, and the rotation resumes once they are back.
+    fn next<'a>(&self, candidates: &[&'a Backend]) -> Option<&'a Backend> {
+        let mut scores = self.scores.lock().unwrap();
+        let total: i64 = candidates.iter().map(|b| b.weight as i64).sum();
+        let mut best: Option<(&'a Backend, i64)> = None;
+        for &backend in candidates {
+            let score = scores.entry(backend.label.clone()).or_default();
+            *score += backend.weight as i64;
+            if best.is_none_or(|(_, best_score)| *score > best_score) {
+                best = Some((backend, *score));
+            }
+        }
+        let (backend, _) = best?;
+        if let Some(score) = scores.get_mut(&backend.label) {
+            *score -= total;
+        }
+        Some(backend)
+    }
+}
+
+/// Points each unit of backend weight owns on the consistent hash ring,
+/// after weights are divided by their greatest common divisor
+const RING_POINTS_PER_WEIGHT: u64 = 64;
+
+/// Most points on the ring; larger weight ratios get fewer points per unit
+const MAX_RING_POINTS: u64 = 1 << 16;
+
+/// Hash ring for the consistent_hash strategy. Each backend owns points in
+/// proportion to its weight, and a key belongs to the first point at or after
+/// its own hash. Hashes are SHA-256 based, so every proxy instance with the
+/// same backends builds the same ring.
+pub struct HashRing {
+    labels: Vec<String>,
+    /// (hash, index into `labels`), sorted by hash
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    pub fn new<'a>(backends: impl IntoIterator<Item = &'a Backend>) -> Self {
+        let backends: Vec<&Backend> = backends.into_iter().collect();
+        let divisor = backends
+            .iter()
+            .fold(0, |divisor, b| gcd(divisor, b.weight as u64))
+            .max(1);
+        let units: u64 = backends.iter().map(|b| b.weight as u64 / divisor).sum();
+        let points_for = |weight: u32| {
+            let units_of = weight as u64 / divisor;
+            if units * RING_POINTS_PER_WEIGHT <= MAX_RING_POINTS {
+                units_of * RING_POINTS_PER_WEIGHT
+            } else {
+                (units_of * MAX_RING_POINTS / units).max(1)
+            }
+        };
+
+        let mut labels = Vec::new();
+        let mut points = Vec::new();
+        for backend in backends {
+            for point in 0..points_for(backend.weight) {
+                let hash = ring_hash(&format!("{}#{}", backend.label, point));
+                points.push((hash, labels.len()));
+            }
+            labels.push(backend.label.clone());
+        }
+        points.sort_unstable();
+        Self { labels, points }
+    }
+
+    /// Backend labels in ring order from `key`'s position, each only once
+    fn walk(&self, key: &str) -> impl Iterator<Item = &str> {
+        let hash = ring_hash(key);
+        let start = self.points.partition_point(|&(point, _)| point < hash);
+        let mut seen = HashSet::new();
+        self.points[start..]
+            .iter()
+            .chain(&self.points[..start])
+            .filter(move |&&(_, index)| seen.insert(index))
+            .map(|&(_, index)| self.labels[index].as_str())
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn ring_hash(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Cumulative weights of the backends that were healthy when it was built,
+/// so weighted selection is a binary search rather than a scan. Rebuilt
+/// only when the routing is swapped or a backend's health changes.
+pub struct WeightedIndex {
+    routing: Arc<Routing>,
+    health_generation: u64,
+    backends: Vec<Arc<Backend>>,
+    cumulative: Vec<u64>,
+}
+
+impl WeightedIndex {
+    fn new(routing: Arc<Routing>, health_state: &HealthState) -> Self {
+        // Read the generation first, so a change racing with the build
+        // leaves the index stale rather than silently missing it
+        let health_generation = health_state.generation();
+        let statuses = health_state.get_all_statuses();
+        let mut total = 0;
+        let (backends, cumulative) = routing
+            .backends
+            .iter()
+            .filter(|b| statuses.get(&b.label).is_none_or(|s| s.healthy))
+            .map(|b| {
+                total += b.weight as u64;
+                (b.clone(), total)
+            })
+            .unzip();
+        Self {
+            routing,
+            health_generation,
+            backends,
+            cumulative,
+        }
+    }
+
+    fn is_current(&self, routing: &Arc<Routing>, health_state: &HealthState) -> bool {
+        Arc::ptr_eq(&self.routing, routing) && self.health_generation == health_state.generation()
+    }
+
+    /// A backend chosen at random in proportion to its configured weight
+    fn pick(&self) -> Option<&Arc<Backend>> {
+        let total = *self.cumulative.last()?;
+        let point = rand::thread_rng().gen_range(0..total);
+        let index = self.cumulative.partition_point(|&end| end <= point);
+        self.backends.get(index)
+    }
+}
+
+/// Backends being removed from rotation. A draining backend's weight ramps
+/// linearly down to zero over the grace period instead of dropping at once,
+/// so traffic shifts smoothly; in-flight requests complete normally.
+pub struct BackendDrains {
+    grace: Duration,
+    started: Mutex<HashMap<String, Instant>>,
+}
+
+impl BackendDrains {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            started: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Start draining `label`. Returns false if it was already draining.
+    pub fn start(&self, label: &str) -> bool {
+        let mut started = self.started.lock().unwrap();
+        if started.contains_key(label) {
+            return false;
+        }
+        info!(
+            "Draining backend {} over {}s",
+            label,
+            self.grace.as_secs_f64()
+        );
+        started.insert(label.to_string(), Instant::now());
+        true
+    }
+
+    /// Stop draining `label`. Returns false if it wasn't draining.
+    pub fn cancel(&self, label: &str) -> bool {
+        self.started.lock().unwrap().remove(label).is_some()
+    }
+
+    /// Whether no backend is draining
+    pub fn is_empty(&self) -> bool {
+        self.started.lock().unwrap().is_empty()
+    }
+
+    /// Fraction (0.0-1.0) of its weight a backend keeps; 1.0 unless draining
+    pub fn factor(&self, label: &str) -> f64 {
+        match self.started.lock().unwrap().get(label) {
+            Some(_) if self.grace.is_zero() => 0.0,
+            Some(started) => {
+                (1.0 - started.elapsed().as_secs_f64() / self.grace.as_secs_f64()).max(0.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    /// Time left until a draining backend stops receiving new requests
+    pub fn remaining(&self, label: &str) -> Option<Duration> {
+        self.started
+            .lock()
+            .unwrap()
+            .get(label)
+            .map(|started| self.grace.saturating_sub(started.elapsed()))
+    }
+}
+
+/// The parts of the configuration that a reload swaps in atomically:
+/// backends and their weights, API keys and method routes
 #[derive(Clone)]
-pub struct AppState {
-    pub client: Client<HttpsConnector<HttpConnector>, Body>,
-    pub backends: Vec<Backend>,
-    pub api_keys: Vec<String>,
-    pub method_routes: HashMap<String, String>,
+pub struct Routing {
+    pub backends: Vec<Arc<Backend>>,
     pub label_to_url: HashMap<String, String>,
+    pub method_routes: HashMap<String, String>,
+    pub api_keys: Arc<ApiKeys>,
+    /// Backends each restricted API key (by key id) may be routed to
+    pub key_backends: HashMap<String, HashSet<String>>,
+    pub backend_rate_limiters: HashMap<String, Arc<TokenBucket>>,
+    /// Limits at `max_rps` minus its headroom; past them a backend only takes
+    /// requests no other backend has room for
+    pub reserved_rate_limiters: HashMap<String, Arc<TokenBucket>>,
+    /// Fraction of requests per backend whose bodies are logged for debugging
+    pub body_sample_rates: HashMap<String, f64>,
+    pub round_robin: Arc<RoundRobin>,
+    pub hash_ring: Arc<HashRing>,
+    /// Backends dropped by a reload that are draining before removal
+    pub removing: HashSet<String>,
+}
+
+impl Routing {
+    pub fn new(config: &Config) -> Self {
+        let api_keys = ApiKeys::new(config.api_keys.clone());
+        let key_backends = config
+            .key_backends
+            .iter()
+            .filter_map(|(key, labels)| {
+                Some((api_keys.id_for(key)?, labels.iter().cloned().collect()))
+            })
+            .collect();
+        Self {
+            backends: config.backends.iter().cloned().map(Arc::new).collect(),
+            label_to_url: config
+                .backends
+                .iter()
+                .map(|b| (b.label.clone(), b.url.clone()))
+                .collect(),
+            method_routes: config.method_routes.clone(),
+            api_keys: Arc::new(api_keys),
+            key_backends,
+            // Per-backend rate limiters for backends with an advertised max_rps
+            backend_rate_limiters: config
+                .backends
+                .iter()
+                .filter_map(|b| {
+                    b.max_rps
+                        .map(|rps| (b.label.clone(), Arc::new(TokenBucket::new(rps))))
+                })
+                .collect(),
+            reserved_rate_limiters: config
+                .backends
+                .iter()
+                .filter_map(|b| {
+                    b.reserved_rps(&config.proxy)
+                        .map(|rps| (b.label.clone(), Arc::new(TokenBucket::with_rate(rps))))
+                })
+                .collect(),
+            body_sample_rates: config
+                .backends
+                .iter()
+                .map(|b| {
+                    let rate = b.body_sample_rate.unwrap_or(config.proxy.body_sample_rate);
+                    (b.label.clone(), rate)
+                })
+                .collect(),
+            round_robin: Arc::new(RoundRobin::new(&config.backends)),
+            hash_ring: Arc::new(HashRing::new(&config.backends)),
+            removing: HashSet::new(),
+        }
+    }
+
+    pub fn backend(&self, label: &str) -> Option<&Arc<Backend>> {
+        self.backends.iter().find(|b| b.label == label)
+    }
+
+    pub fn labels(&self) -> Vec<String> {
+        self.backends.iter().map(|b| b.label.clone()).collect()
+    }
+
+    /// Routing for a reloaded `config`. Backends it no longer lists are kept,
+    /// marked as removing, so they can drain before being dropped.
+    fn reloaded(&self, config: &Config) -> Self {
+        let mut next = Self::new(config);
+        for backend in &self.backends {
+            if next.label_to_url.contains_key(&backend.label) {
+                continue;
+            }
+            let label = &backend.label;
+            next.label_to_url.insert(label.clone(), backend.url.clone());
+            if let Some(bucket) = self.backend_rate_limiters.get(label) {
+                next.backend_rate_limiters
+                    .insert(label.clone(), bucket.clone());
+            }
+            if let Some(bucket) = self.reserved_rate_limiters.get(label) {
+                next.reserved_rate_limiters
+                    .insert(label.clone(), bucket.clone());
+            }
+            if let Some(&rate) = self.body_sample_rates.get(label) {
+                next.body_sample_rates.insert(label.clone(), rate);
+            }
+            next.removing.insert(label.clone());
+            next.backends.push(backend.clone());
+        }
+        next.round_robin = Arc::new(RoundRobin::new(next.backends.iter().map(AsRef::as_ref)));
+        next.hash_ring = Arc::new(HashRing::new(next.backends.iter().map(AsRef::as_ref)));
+        next
+    }
+
+    /// Label of the backend `method_routes` sends `method` to. Routes to a
+    /// removed backend are pruned by `without`; any other route whose target
+    /// isn't configured is ignored, so the method falls back to normal
+    /// selection instead of routing to nowhere.
+    pub fn method_route(&self, method: &str) -> Option<&str> {
+        self.method_routes
+            .get(method)
+            .filter(|label| self.label_to_url.contains_key(*label))
+            .map(String::as_str)
+    }
+
+    /// Method to route a multi-request batch by. If every method in the batch
+    /// that has a `method_routes` entry is routed to the same backend, one of
+    /// them is returned so the whole batch follows that route. Batches without
+    /// routed methods, or whose methods are routed to different backends, get
+    /// None and go through normal selection.
+    pub fn batch_route_method(&self, methods: &[String]) -> Option<String> {
+        let mut routed = methods
+            .iter()
+            .filter_map(|method| Some((method, self.method_route(method)?)));
+        let (method, label) = routed.next()?;
+        if let Some((other, other_label)) = routed.find(|(_, l)| *l != label) {
+            info!(
+                "Batch methods {} and {} are routed to different backends ({} and {}), using normal selection",
+                method, other, label, other_label
+            );
+            return None;
+        }
+        Some(method.clone())
+    }
+
+    /// Routing with the removed backend `label` dropped, along with any
+    /// method routes to it
+    fn without(&self, label: &str) -> Self {
+        let mut next = self.clone();
+        next.backends.retain(|b| b.label != label);
+        next.method_routes.retain(|_, target| target != label);
+        next.label_to_url.remove(label);
+        next.backend_rate_limiters.remove(label);
+        next.reserved_rate_limiters.remove(label);
+        next.body_sample_rates.remove(label);
+        next.removing.remove(label);
+        next.round_robin = Arc::new(RoundRobin::new(next.backends.iter().map(AsRef::as_ref)));
+        next.hash_ring = Arc::new(HashRing::new(next.backends.iter().map(AsRef::as_ref)));
+        next
+    }
+}
+
+/// Random pick among `candidates` in proportion to `weight`, computed once
+/// per backend. Backends weighted zero (drained, or weight 0) are never
+/// picked, and None is returned when no candidate has a positive weight.
+fn pick_by_weight<'a>(
+    candidates: &[&'a Backend],
+    weight: impl Fn(&Backend) -> f64,
+) -> Option<&'a Backend> {
+    let weighted: Vec<(&'a Backend, f64)> = candidates
+        .iter()
+        .map(|b| (*b, weight(b)))
+        .filter(|(_, weight)| *weight > 0.0)
+        .collect();
+    let total: f64 = weighted.iter().map(|(_, weight)| weight).sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut random_weight = rand::thread_rng().gen_range(0.0..total);
+    for (backend, weight) in &weighted {
+        if random_weight < *weight {
+            return Some(backend);
+        }
+        random_weight -= weight;
+    }
+    // Float rounding can leave a sliver past the last weight
+    weighted.last().map(|(backend, _)| *backend)
+}
+
+/// Power of two choices: two distinct weighted random candidates, of which
+/// the one with fewer in-flight requests is chosen
+fn pick_two_choices<'a>(
+    candidates: &[&'a Backend],
+    weight: impl Fn(&Backend) -> f64,
+    in_flight: impl Fn(&str) -> usize,
+) -> Option<&'a Backend> {
+    let first = pick_by_weight(candidates, &weight)?;
+    let others: Vec<&Backend> = candidates
+        .iter()
+        .copied()
+        .filter(|b| b.label != first.label)
+        .collect();
+    let Some(second) = pick_by_weight(&others, &weight) else {
+        return Some(first);
+    };
+    if in_flight(&second.label) < in_flight(&first.label) {
+        Some(second)
+    } else {
+        Some(first)
+    }
+}
+
+/// Whether the backend's bucket in `limiters`, if it has one, has a token
+fn has_token(limiters: &HashMap<String, Arc<TokenBucket>>, label: &str) -> bool {
+    limiters
+        .get(label)
+        .map(|bucket| bucket.has_token())
+        .unwrap_or(true)
+}
+
+/// Header names from the config, which `load_config` has validated
+fn header_names(names: &[String]) -> Vec<HeaderName> {
+    names
+        .iter()
+        .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        .collect()
+}
+
+pub struct AppState {
+    pub clients: Arc<BackendClients>,
+    pub srv_endpoints: Arc<SrvEndpoints>,
+    pub routing: Arc<ArcSwap<Routing>>,
+    pub admin_api_key: Option<String>,
+    /// Query parameter names a client API key is accepted under
+    pub key_params: Vec<String>,
+    /// Renders `/metrics`; None unless `metrics_enabled`
+    pub prometheus: Option<PrometheusHandle>,
+    pub api_key_metrics: ApiKeyMetrics,
+    pub method_metrics: MethodMetrics,
+    pub backend_metrics: BackendMetrics,
+    pub log_format: LogFormat,
+    pub log_template: Option<LogTemplate>,
+    pub recent_requests: RecentRequests,
+    pub allowed_methods: HashSet<String>,
+    pub blocked_methods: HashSet<String>,
     pub health_state: Arc<HealthState>,
+    pub method_rate_limiter: MethodRateLimiter,
+    pub key_rate_limiter: KeyRateLimiter,
+    pub ip_rate_limiter: Option<IpRateLimiter>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub forward_client_ip: bool,
+    pub forward_headers: Vec<HeaderName>,
+    pub allow_backend_override: bool,
+    pub strip_headers: Vec<HeaderName>,
+    /// Message of the JSON-RPC error returned to rate-limited clients
+    pub rate_limit_message: String,
     pub proxy_timeout_secs: u64,
+    pub total_timeout_secs: Option<u64>,
+    /// Slow-start period after a backend turns healthy
+    pub warmup: Option<Duration>,
+    /// How long a request waits for a healthy backend before a 503
+    pub wait_for_backend: Option<Duration>,
+    /// Reject requests that aren't well-formed JSON-RPC 2.0
+    pub strict_jsonrpc: bool,
+    pub max_body_bytes: usize,
+    pub max_response_bytes: usize,
+    pub allow_get: bool,
+    pub get_methods: Vec<String>,
+    pub timeout_jitter_ms: u64,
+    pub response_stream_timeout_secs: Option<u64>,
+    pub notification_no_content: bool,
+    pub compact_request_body: bool,
+    pub diag_header: bool,
+    pub dead_letter: Option<DeadLetterLog>,
+    pub dead_letter_methods: Vec<String>,
+    pub require_result_methods: Vec<String>,
+    pub max_retries: u32,
+    pub retry_status_codes: Vec<u16>,
+    pub redirect_policy: RedirectPolicy,
+    pub max_redirects: u32,
+    pub follow_redirect_methods: Vec<String>,
+    /// Methods advertised by a locally answered `rpc.discover`; `None` when
+    /// `rpc.discover` is forwarded like any other method
+    pub rpc_discover_methods: Option<Vec<String>>,
+    pub health_check: HealthCheckConfig,
+    pub connection_affinity: Option<ConnectionAffinity>,
+    pub failover_trip: Option<FailoverTrip>,
+    pub success_rate_weights: Option<SuccessRateWeights>,
+    pub circuit_breakers: Option<CircuitBreakers>,
+    pub drains: BackendDrains,
+    pub strategy: Strategy,
+    /// Param position hashed by the consistent_hash strategy, per method
+    pub hash_key_params: HashMap<String, usize>,
+    /// Built lazily by the first weighted selection
+    pub weighted_index: ArcSwapOption<WeightedIndex>,
+    pub in_flight: InFlight,
+    pub latency: LatencyEwma,
+    pub transaction_dedup: Option<TransactionDedup>,
+    pub response_cache: ResponseCache,
 }
 
 impl AppState {
-    pub fn select_backend(&self, rpc_method: Option<&str>) -> Option<(&str, &str)> {
+    /// State for serving `config`. The shared handles are built by the caller,
+    /// which also hands them to the background tasks.
+    pub fn new(
+        config: &Config,
+        routing: Arc<ArcSwap<Routing>>,
+        health_state: Arc<HealthState>,
+        clients: Arc<BackendClients>,
+        srv_endpoints: Arc<SrvEndpoints>,
+        dead_letter: Option<DeadLetterLog>,
+        prometheus: Option<PrometheusHandle>,
+    ) -> Self {
+        let rpc_discover_methods = config.proxy.answer_rpc_discover.then(|| {
+            let mut methods: Vec<String> = config
+                .proxy
+                .discover_methods
+                .iter()
+                .chain(config.method_routes.keys())
+                .chain(config.method_rate_limits.keys())
+                .cloned()
+                .collect();
+            methods.sort();
+            methods.dedup();
+            methods
+        });
+
+        let api_keys = routing.load().api_keys.clone();
+        let backend_labels = routing.load().labels();
+        let key_rate_limits = config
+            .rate_limits
+            .iter()
+            .filter_map(|(key, rps)| Some((api_keys.id_for(key)?, *rps)))
+            .collect();
+        Self {
+            clients,
+            srv_endpoints,
+            routing,
+            prometheus,
+            api_key_metrics: ApiKeyMetrics::new(&api_keys.ids()),
+            backend_metrics: BackendMetrics::new(&backend_labels),
+            method_metrics: MethodMetrics::new(
+                config
+                    .method_routes
+                    .keys()
+                    .chain(config.method_rate_limits.keys())
+                    .chain(config.cache.slot_ttl.keys())
+                    .chain(&config.cache.methods)
+                    .chain(&config.proxy.require_result_methods)
+                    .chain(&config.proxy.discover_methods),
+            ),
+            admin_api_key: config.admin_api_key.clone(),
+            key_params: config.auth.key_params.clone(),
+            log_format: config.log_format,
+            log_template: config
+                .log_template
+                .as_deref()
+                .map(|template| LogTemplate::parse(template).expect("Invalid log_template")),
+            recent_requests: RecentRequests::new(config.recent_requests_size),
+            allowed_methods: config.allowed_methods.iter().cloned().collect(),
+            blocked_methods: config.blocked_methods.iter().cloned().collect(),
+            health_state,
+            method_rate_limiter: MethodRateLimiter::new(config.method_rate_limits.clone()),
+            key_rate_limiter: KeyRateLimiter::new(key_rate_limits),
+            ip_rate_limiter: config.proxy.ip_rate_limit.map(IpRateLimiter::new),
+            trusted_proxies: config.proxy.trusted_proxies.clone(),
+            forward_client_ip: config.proxy.forward_client_ip,
+            forward_headers: header_names(&config.proxy.forward_headers),
+            allow_backend_override: config.proxy.allow_backend_override,
+            strip_headers: header_names(&config.proxy.strip_headers),
+            rate_limit_message: config.rate_limit.message.clone(),
+            proxy_timeout_secs: config.proxy.timeout_secs,
+            total_timeout_secs: config.proxy.total_timeout_secs,
+            warmup: config.proxy.warmup_secs.map(Duration::from_secs),
+            wait_for_backend: config.proxy.wait_for_backend_ms.map(Duration::from_millis),
+            strict_jsonrpc: config.proxy.strict_jsonrpc,
+            max_body_bytes: config.proxy.max_body_bytes,
+            max_response_bytes: config.proxy.max_response_bytes,
+            allow_get: config.proxy.allow_get,
+            get_methods: config.proxy.get_methods.clone(),
+            timeout_jitter_ms: config.proxy.timeout_jitter_ms,
+            response_stream_timeout_secs: config.proxy.response_stream_timeout_secs,
+            notification_no_content: config.proxy.notification_no_content,
+            compact_request_body: config.proxy.compact_request_body,
+            diag_header: config.proxy.diag_header,
+            dead_letter,
+            dead_letter_methods: config.proxy.dead_letter_methods.clone(),
+            require_result_methods: config.proxy.require_result_methods.clone(),
+            max_retries: config.proxy.max_retries,
+            retry_status_codes: config.proxy.retry_status_codes.clone(),
+            redirect_policy: config.proxy.redirect_policy,
+            max_redirects: config.proxy.max_redirects,
+            follow_redirect_methods: config.proxy.follow_redirect_methods.clone(),
+            rpc_discover_methods,
+            health_check: config.health_check.clone(),
+            failover_trip: config
+                .proxy
+                .global_failover_trip
+                .clone()
+                .map(FailoverTrip::new),
+            success_rate_weights: config
+                .proxy
+                .adaptive_weight
+                .clone()
+                .map(SuccessRateWeights::new),
+            drains: BackendDrains::new(Duration::from_secs(config.proxy.drain_grace_secs)),
+            strategy: config.proxy.strategy,
+            hash_key_params: config.proxy.hash_key_params.clone(),
+            weighted_index: ArcSwapOption::empty(),
+            in_flight: InFlight::new(&backend_labels),
+            latency: LatencyEwma::new(config.proxy.latency_alpha),
+            circuit_breakers: config.proxy.breaker_threshold.map(|threshold| {
+                CircuitBreakers::new(
+                    threshold,
+                    Duration::from_secs(config.proxy.breaker_cooldown_secs),
+                )
+            }),
+            transaction_dedup: config
+                .proxy
+                .send_transaction_dedup_secs
+                .map(|secs| TransactionDedup::new(Duration::from_secs(secs))),
+            response_cache: ResponseCache::new(&config.cache),
+            connection_affinity: config.proxy.connection_affinity.then(|| {
+                ConnectionAffinity::new(Duration::from_secs(
+                    config.proxy.connection_affinity_idle_secs,
+                ))
+            }),
+        }
+    }
+
+    /// Swap in the routing for a reloaded `config`. Backends the config no
+    /// longer lists start draining; their labels are returned so the caller
+    /// can drop them with `finish_removal` once the drain grace has passed.
+    pub fn reload(&self, config: &Config) -> Vec<String> {
+        let previous = self.routing.rcu(|routing| routing.reloaded(config));
+        let routing = self.routing.load();
+
+        let labels = routing.labels();
+        self.health_state.register(&labels);
+        self.health_state.set_unchecked(&config.backends);
+        self.in_flight.register(&labels);
+        self.backend_metrics.register(&labels);
+        self.api_key_metrics.register(&routing.api_keys.ids());
+        self.clients.reload(config);
+
+        // A backend listed again before its removal finished stays
+        for label in previous.removing.difference(&routing.removing) {
+            if self.drains.cancel(label) {
+                info!(
+                    "Backend {} is configured again, no longer removing it",
+                    label
+                );
+            }
+        }
+        let removed: Vec<String> = routing
+            .removing
+            .difference(&previous.removing)
+            .cloned()
+            .collect();
+        for label in &removed {
+            info!("Backend {} was removed from the config", label);
+            self.drains.start(label);
+        }
+        removed
+    }
+
+    /// Drop a backend removed by a reload once it has finished draining
+    pub fn finish_removal(&self, label: &str) {
+        if self.drains.factor(label) > 0.0 {
+            return;
+        }
+        let previous = self.routing.rcu(|routing| {
+            if routing.removing.contains(label) {
+                Arc::new(routing.without(label))
+            } else {
+                Arc::clone(routing)
+            }
+        });
+        if previous.removing.contains(label) {
+            self.drains.cancel(label);
+            self.health_state.remove(label);
+            info!("Removed backend {}", label);
+            for (method, _) in previous.method_routes.iter().filter(|(_, l)| *l == label) {
+                warn!(
+                    "Method {} was routed to removed backend {}, now using normal selection",
+                    method, label
+                );
+            }
+        }
+    }
+
+    fn is_healthy(&self, label: &str) -> bool {
+        self.health_state
+            .get_status(label)
+            .map(|s| s.healthy)
+            .unwrap_or(true) // Default to healthy if status not found
+    }
+
+    /// Upstream timeout for one attempt, including random jitter so that
+    /// requests started together don't all time out (and retry) together
+    pub fn upstream_timeout(&self) -> Duration {
+        let jitter = if self.timeout_jitter_ms > 0 {
+            rand::thread_rng().gen_range(0..=self.timeout_jitter_ms)
+        } else {
+            0
+        };
+        Duration::from_secs(self.proxy_timeout_secs) + Duration::from_millis(jitter)
+    }
+
+    /// Record the outcome of one upstream attempt to `label` for metrics, the
+    /// global failover trip, adaptive weights, circuit breakers and health
+    pub fn record_attempt(&self, label: &str, outcome: Result<(), BackendError>) {
+        let success = outcome.is_ok();
+        self.backend_metrics.record(label, outcome.as_ref().err());
+        if let Some(ref weights) = self.success_rate_weights {
+            weights.record(label, success);
+        }
+        // A throttled backend isn't broken: neither outcome reaches the breakers
+        if outcome
+            .as_ref()
+            .err()
+            .is_none_or(BackendError::trips_breaker)
+        {
+            if let Some(ref trip) = self.failover_trip {
+                trip.record(success);
+            }
+            if let Some(ref breakers) = self.circuit_breakers {
+                breakers.record(label, success);
+            }
+        }
+        let Err(error) = outcome else {
+            return;
+        };
+        if !error.marks_unhealthy() {
+            self.health_state.record_error(label, error.health_error());
+        } else if self.health_state.record_failure(
+            label,
+            error.health_error(),
+            self.failures_threshold(label),
+        ) {
+            self.clients.evict(label);
+        }
+    }
+
+    /// Whether the backend may take new requests: its circuit breaker lets
+    /// requests through and it hasn't finished draining
+    fn accepts_requests(&self, label: &str) -> bool {
+        self.drains.factor(label) > 0.0
+            && self
+                .circuit_breakers
+                .as_ref()
+                .map(|breakers| breakers.allows(label))
+                .unwrap_or(true)
+    }
+
+    /// Selection weight of a backend: its configured weight, scaled by its
+    /// recent error rate and latency when adaptive weighting is enabled
+    pub fn effective_weight(&self, backend: &Backend) -> f64 {
+        let factor = self
+            .success_rate_weights
+            .as_ref()
+            .map(|weights| weights.factor(&backend.label, self.latency.get(&backend.label)))
+            .unwrap_or(1.0);
+        backend.weight as f64
+            * factor
+            * self.warmup_factor(&backend.label)
+            * self.drains.factor(&backend.label)
+    }
+
+    /// Fraction of its weight a backend receives while warming up; 1.0 when
+    /// slow-start is disabled
+    pub fn warmup_factor(&self, label: &str) -> f64 {
+        self.warmup
+            .map(|warmup| self.health_state.warmup_factor(label, warmup))
+            .unwrap_or(1.0)
+    }
+
+    /// Whether a failed request may be retried on another backend
+    pub fn failover_allowed(&self, attempts: usize) -> bool {
+        attempts <= self.max_retries as usize
+            && !self
+                .failover_trip
+                .as_ref()
+                .is_some_and(|trip| trip.is_tripped())
+    }
+
+    /// Decide whether to log the request and response bodies for a request
+    /// sent to `label`
+    pub fn sample_body(&self, label: &str) -> bool {
+        match self.routing.load().body_sample_rates.get(label) {
+            Some(&rate) if rate > 0.0 => rand::thread_rng().gen_bool(rate),
+            _ => false,
+        }
+    }
+
+    /// Consecutive failures before `label` is marked unhealthy
+    pub fn failures_threshold(&self, label: &str) -> u32 {
+        self.backend(label)
+            .map(|b| b.failures_threshold(&self.health_check))
+            .unwrap_or(self.health_check.consecutive_failures_threshold)
+    }
+
+    /// How many redirects to follow upstream for a request to `rpc_method`
+    pub fn redirects_to_follow(&self, rpc_method: Option<&str>) -> u32 {
+        let follow = self.redirect_policy == RedirectPolicy::Follow
+            && (self.follow_redirect_methods.is_empty()
+                || rpc_method.is_some_and(|method| {
+                    self.follow_redirect_methods.iter().any(|m| m == method)
+                }));
+        if follow {
+            self.max_redirects
+        } else {
+            0
+        }
+    }
+
+    /// Whether a selection may spend reserved `max_rps` headroom: only once
+    /// none of the backends it could pick (not `excluded`, within `allowed`)
+    /// has room below its reserve. Computed once per selection.
+    fn headroom_open(
+        &self,
+        routing: &Routing,
+        excluded: &[String],
+        allowed: Option<&HashSet<String>>,
+    ) -> bool {
+        !routing.reserved_rate_limiters.is_empty()
+            && !routing.backends.iter().any(|b| {
+                !excluded.contains(&b.label)
+                    && allowed.is_none_or(|a| a.contains(&b.label))
+                    && self.is_healthy(&b.label)
+                    && self.accepts_requests(&b.label)
+                    && has_token(&routing.backend_rate_limiters, &b.label)
+                    && has_token(&routing.reserved_rate_limiters, &b.label)
+            })
+    }
+
+    /// Whether the backend's advertised rate limit leaves room for a request,
+    /// counting its reserved headroom only when `headroom_open`
+    fn has_capacity(&self, label: &str, headroom_open: bool) -> bool {
+        let routing = self.routing.load();
+        has_token(&routing.backend_rate_limiters, label)
+            && (headroom_open || has_token(&routing.reserved_rate_limiters, label))
+    }
+
+    /// Claim the circuit breaker's trial request if it is half-open, then
+    /// consume a token from the backend's rate limiter, if it has one. The
+    /// breaker goes first so a request it rejects doesn't spend a token.
+    pub fn acquire_backend_capacity(&self, label: &str) -> bool {
+        let breakers = self.circuit_breakers.as_ref();
+        if breakers.is_some_and(|breakers| !breakers.acquire(label)) {
+            return false;
+        }
+        let routing = self.routing.load();
+        let acquired = routing
+            .backend_rate_limiters
+            .get(label)
+            .map(|bucket| bucket.try_acquire())
+            .unwrap_or(true);
+        if !acquired {
+            if let Some(breakers) = breakers {
+                breakers.release_trial(label);
+            }
+            return false;
+        }
+        // An empty reserved bucket means this request spends headroom
+        if let Some(bucket) = routing.reserved_rate_limiters.get(label) {
+            bucket.try_acquire();
+        }
+        true
+    }
+
+    /// When healthy backends exist but all of them are out of rate-limit
+    /// tokens, return how long until the first one has capacity again
+    pub fn rate_limited_retry_after(&self) -> Option<Duration> {
+        let routing = self.routing.load();
+        let healthy: Vec<&Arc<Backend>> = routing
+            .backends
+            .iter()
+            .filter(|b| self.is_healthy(&b.label))
+            .collect();
+        let headroom = self.headroom_open(&routing, &[], None);
+        if healthy.is_empty()
+            || healthy
+                .iter()
+                .any(|b| self.has_capacity(&b.label, headroom))
+        {
+            return None;
+        }
+        healthy
+            .iter()
+            .filter_map(|b| routing.backend_rate_limiters.get(&b.label))
+            .map(|bucket| bucket.retry_after())
+            .min()
+    }
+
+    pub fn backend(&self, label: &str) -> Option<Arc<Backend>> {
+        self.routing.load().backend(label).cloned()
+    }
+
+    /// URL of the endpoint to send the next request for `label` to, resolving
+    /// SRV backends to one of their current targets
+    pub fn endpoint_url(&self, label: &str, url: &str) -> String {
+        match self.backend(label) {
+            Some(backend) => self.srv_endpoints.url_for(&backend),
+            None => url.to_string(),
+        }
+    }
+
+    /// Select a backend for a request from client connection `peer`. With
+    /// connection affinity enabled, the backend that served the connection's
+    /// previous request is reused while it remains available. Method routes
+    /// take precedence over affinity.
+    pub fn select_backend_for_connection(
+        &self,
+        peer: SocketAddr,
+        rpc_method: Option<&str>,
+        hash_key: Option<&str>,
+        excluded: &[String],
+        allowed: Option<&HashSet<String>>,
+    ) -> Option<(String, String)> {
+        let Some(affinity) = &self.connection_affinity else {
+            return self.select_backend(rpc_method, hash_key, excluded, allowed);
+        };
+        if rpc_method.is_some_and(|m| self.routing.load().method_route(m).is_some()) {
+            return self.select_backend(rpc_method, hash_key, excluded, allowed);
+        }
+
+        let headroom = self.headroom_open(&self.routing.load(), excluded, allowed);
+        if let Some(backend) = affinity
+            .get(&peer)
+            .and_then(|label| self.backend(&label))
+            .filter(|b| {
+                !excluded.contains(&b.label)
+                    && allowed.is_none_or(|allowed| allowed.contains(&b.label))
+                    && self.is_healthy(&b.label)
+                    && self.accepts_requests(&b.label)
+                    && self.has_capacity(&b.label, headroom)
+            })
+        {
+            affinity.pin(peer, &backend.label);
+            return Some((backend.label.clone(), backend.url.clone()));
+        }
+
+        let selection = self.select_backend(rpc_method, hash_key, excluded, allowed)?;
+        affinity.pin(peer, &selection.0);
+        Some(selection)
+    }
+
+    /// The backend a client asked for with `X-Backend`, if it exists, the API
+    /// key may use it and it is available; otherwise None, so the request
+    /// falls back to normal selection
+    pub fn select_override(
+        &self,
+        label: &str,
+        allowed: Option<&HashSet<String>>,
+    ) -> Option<(String, String)> {
+        let headroom = self.headroom_open(&self.routing.load(), &[], allowed);
+        let available = self.backend(label).filter(|b| {
+            allowed.is_none_or(|allowed| allowed.contains(&b.label))
+                && self.is_healthy(&b.label)
+                && self.accepts_requests(&b.label)
+                && self.has_capacity(&b.label, headroom)
+        });
+        match available {
+            Some(backend) => {
+                info!("Backend override: request pinned to label={}", label);
+                Some((backend.label.clone(), backend.url.clone()))
+            }
+            None => {
+                info!(
+                    "Backend override label={} is unknown or unavailable, using normal selection",
+                    label
+                );
+                None
+            }
+        }
+    }
+
+    /// Whether `allowed_methods` and `blocked_methods` let `method` through
+    pub fn method_allowed(&self, method: &str) -> bool {
+        !self.blocked_methods.contains(method)
+            && (self.allowed_methods.is_empty() || self.allowed_methods.contains(method))
+    }
+
+    /// Key the consistent_hash strategy places a request by: the configured
+    /// param of the method, or its first element if that param is an array
+    pub fn hash_key(&self, method: &str, request: &serde_json::Value) -> Option<String> {
+        if self.strategy != Strategy::ConsistentHash {
+            return None;
+        }
+        let position = *self.hash_key_params.get(method)?;
+        let param = request.get("params")?.get(position)?;
+        let param = match param {
+            serde_json::Value::Array(items) => items.first()?,
+            param => param,
+        };
+        param.as_str().map(str::to_string)
+    }
+
+    /// Select a backend for a request, skipping any label in `excluded`
+    /// (backends already tried for this request) and, when `allowed` is
+    /// given, any label not in it (backends the API key may not use).
+    /// Requests with a `hash_key` go to the first available backend on the
+    /// hash ring from the key's position.
+    pub fn select_backend(
+        &self,
+        rpc_method: Option<&str>,
+        hash_key: Option<&str>,
+        excluded: &[String],
+        allowed: Option<&HashSet<String>>,
+    ) -> Option<(String, String)> {
+        let routing = self.routing.load();
+        let permitted = |label: &str| {
+            !excluded.iter().any(|e| e == label) && allowed.is_none_or(|a| a.contains(label))
+        };
+        let headroom = self.headroom_open(&routing, excluded, allowed);
+
         // Check method-specific routing first
         if let Some(method) = rpc_method {
-            if let Some(backend_label) = self.method_routes.get(method) {
-                if let Some(backend_url) = self.label_to_url.get(backend_label) {
+            if let Some(backend_label) = routing.method_route(method).filter(|l| permitted(l)) {
+                if let Some(backend_url) = routing.label_to_url.get(backend_label) {
                     // Check if method-routed backend is healthy
                     if let Some(status) = self.health_state.get_status(backend_label) {
-                        if status.healthy {
-                            info!("Method {} routed to label={}", method, backend_label);
-                            return Some((backend_label, backend_url));
-                        } else {
+                        if !status.healthy {
                             info!(
                                 "Method {} routed to label={} but backend is unhealthy, falling back to weighted selection",
                                 method, backend_label
                             );
+                        } else if !self.accepts_requests(backend_label) {
+                            info!(
+                                "Method {} routed to label={} but its circuit is open or it has drained, falling back to weighted selection",
+                                method, backend_label
+                            );
+                        } else if !self.has_capacity(backend_label, headroom) {
+                            info!(
+                                "Method {} routed to label={} but backend is at its max_rps, falling back to weighted selection",
+                                method, backend_label
+                            );
+                        } else {
+                            info!("Method {} routed to label={}", method, backend_label);
+                            return Some((backend_label.to_string(), backend_url.clone()));
                         }
                     }
                 }
             }
         }
 
-        // Filter out unhealthy backends
-        let healthy_backends: Vec<&Backend> = self
+        if let Some(key) = hash_key {
+            let label = routing.hash_ring.walk(key).find(|label| {
+                permitted(label)
+                    && self.is_healthy(label)
+                    && self.accepts_requests(label)
+                    && self.has_capacity(label, headroom)
+            })?;
+            return Some((label.to_string(), routing.label_to_url.get(label)?.clone()));
+        }
+
+        if let Some(backend) = self.pick_weighted_indexed(&routing, excluded, allowed, headroom) {
+            return Some((backend.label.clone(), backend.url.clone()));
+        }
+
+        // Filter out unhealthy and rate-limited backends
+        let healthy_backends: Vec<&Backend> = routing
             .backends
             .iter()
+            .map(AsRef::as_ref)
             .filter(|b| {
-                self.health_state
-                    .get_status(&b.label)
-                    .map(|s| s.healthy)
-                    .unwrap_or(true) // Default to healthy if status not found
+                permitted(&b.label)
+                    && self.is_healthy(&b.label)
+                    && self.accepts_requests(&b.label)
+                    && self.has_capacity(&b.label, headroom)
             })
             .collect();
 
@@ -57,39 +1575,146 @@ impl AppState {
             return None; // No healthy backends available
         }
 
-        // Calculate total weight of healthy backends
-        let healthy_total_weight: u32 = healthy_backends.iter().map(|b| b.weight).sum();
+        // Fall back to a lower tier only when every backend above it is out
+        let healthy_backends = if self.strategy == Strategy::Priority {
+            let tier = healthy_backends.iter().map(|b| b.priority).min()?;
+            healthy_backends
+                .into_iter()
+                .filter(|b| b.priority == tier)
+                .collect()
+        } else {
+            healthy_backends
+        };
 
-        // Weighted random selection among healthy backends
-        let mut rng = rand::thread_rng();
-        let mut random_weight = rng.gen_range(0..healthy_total_weight);
+        // On retries, prefer backends in a different zone than the ones that
+        // already failed, to avoid correlated failures
+        let failed_zones: Vec<&str> = excluded
+            .iter()
+            .filter_map(|label| routing.backend(label)?.zone.as_deref())
+            .collect();
+        let healthy_backends = if failed_zones.is_empty() {
+            healthy_backends
+        } else {
+            let other_zones: Vec<&Backend> = healthy_backends
+                .iter()
+                .copied()
+                .filter(|b| {
+                    b.zone
+                        .as_deref()
+                        .is_none_or(|zone| !failed_zones.contains(&zone))
+                })
+                .collect();
+            if other_zones.is_empty() {
+                healthy_backends
+            } else {
+                other_zones
+            }
+        };
 
-        for backend in &healthy_backends {
-            if random_weight < backend.weight {
-                return Some((&backend.label, &backend.url));
+        let backend = match self.strategy {
+            Strategy::Weighted | Strategy::ConsistentHash | Strategy::Priority => {
+                self.pick_weighted(&healthy_backends)
             }
-            random_weight -= backend.weight;
+            Strategy::LeastConnections => self.pick_least_connections(&healthy_backends),
+            Strategy::RoundRobin => routing.round_robin.next(&healthy_backends),
+            Strategy::Latency => self.pick_by_latency(&healthy_backends),
+            Strategy::P2c => self.pick_power_of_two(&healthy_backends),
+        }?;
+        Some((backend.label.clone(), backend.url.clone()))
+    }
+
+    /// Weighted random selection through the `WeightedIndex`, for first
+    /// attempts when weights are just the configured ones. The pick is only
+    /// used if the backend is still available; otherwise None is returned and
+    /// the caller falls back to scanning, which together still selects each
+    /// available backend in proportion to its weight.
+    fn pick_weighted_indexed(
+        &self,
+        routing: &Arc<Routing>,
+        excluded: &[String],
+        allowed: Option<&HashSet<String>>,
+        headroom: bool,
+    ) -> Option<Arc<Backend>> {
+        if !matches!(self.strategy, Strategy::Weighted | Strategy::ConsistentHash)
+            || !excluded.is_empty()
+            || self.success_rate_weights.is_some()
+            || !self.drains.is_empty()
+            || self
+                .warmup
+                .is_some_and(|warmup| self.health_state.is_warming(warmup))
+        {
+            return None;
         }
+        let index = match self.weighted_index.load_full() {
+            Some(index) if index.is_current(routing, &self.health_state) => index,
+            _ => {
+                let index = Arc::new(WeightedIndex::new(routing.clone(), &self.health_state));
+                self.weighted_index.store(Some(index.clone()));
+                index
+            }
+        };
+        index
+            .pick()
+            .filter(|b| {
+                allowed.is_none_or(|a| a.contains(&b.label))
+                    && self.is_healthy(&b.label)
+                    && self.accepts_requests(&b.label)
+                    && self.has_capacity(&b.label, headroom)
+            })
+            .cloned()
+    }
 
-        // Fallback (should never reach here if weights are valid)
-        healthy_backends
-            .first()
-            .map(|b| (b.label.as_str(), b.url.as_str()))
+    /// Weighted random selection
+    fn pick_weighted<'a>(&self, candidates: &[&'a Backend]) -> Option<&'a Backend> {
+        pick_by_weight(candidates, |b| self.effective_weight(b))
+    }
+
+    /// Weighted random selection with each weight divided by the backend's
+    /// average latency, so faster backends get a larger share
+    fn pick_by_latency<'a>(&self, candidates: &[&'a Backend]) -> Option<&'a Backend> {
+        pick_by_weight(candidates, |b| {
+            self.effective_weight(b) / self.latency.estimate(&b.label)
+        })
+    }
+
+    /// Power of two choices among `candidates`
+    fn pick_power_of_two<'a>(&self, candidates: &[&'a Backend]) -> Option<&'a Backend> {
+        pick_two_choices(
+            candidates,
+            |b| self.effective_weight(b),
+            |label| self.in_flight.count(label),
+        )
+    }
+
+    /// The backend with the fewest in-flight requests, ties going to the
+    /// higher weight
+    fn pick_least_connections<'a>(&self, candidates: &[&'a Backend]) -> Option<&'a Backend> {
+        candidates.iter().copied().min_by(|a, b| {
+            self.in_flight
+                .count(&a.label)
+                .cmp(&self.in_flight.count(&b.label))
+                .then(
+                    self.effective_weight(b)
+                        .total_cmp(&self.effective_weight(a)),
+                )
+        })
     }
 
     /// Select a healthy backend that has WebSocket support (ws_url configured)
-    pub fn select_ws_backend(&self) -> Option<(&str, &str)> {
-        // Filter to backends with ws_url configured and healthy
-        let ws_backends: Vec<&Backend> = self
+    pub fn select_ws_backend(&self, allowed: Option<&HashSet<String>>) -> Option<(String, String)> {
+        // Filter to backends with ws_url configured that the key may use and
+        // that would take an HTTP request right now
+        let routing = self.routing.load();
+        let headroom = self.headroom_open(&routing, &[], allowed);
+        let ws_backends: Vec<&Arc<Backend>> = routing
             .backends
             .iter()
             .filter(|b| {
                 b.ws_url.is_some()
-                    && self
-                        .health_state
-                        .get_status(&b.label)
-                        .map(|s| s.healthy)
-                        .unwrap_or(true)
+                    && allowed.is_none_or(|a| a.contains(&b.label))
+                    && self.is_healthy(&b.label)
+                    && self.accepts_requests(&b.label)
+                    && self.has_capacity(&b.label, headroom)
             })
             .collect();
 
@@ -106,20 +1731,252 @@ impl AppState {
 
         for backend in &ws_backends {
             if random_weight < backend.weight {
-                return Some((
-                    backend.label.as_str(),
-                    backend.ws_url.as_ref().unwrap().as_str(),
-                ));
+                return Some((backend.label.clone(), backend.ws_url.clone().unwrap()));
             }
             random_weight -= backend.weight;
         }
 
         // Fallback
-        ws_backends.first().map(|b| {
-            (
-                b.label.as_str(),
-                b.ws_url.as_ref().unwrap().as_str(),
+        ws_backends
+            .first()
+            .map(|b| (b.label.clone(), b.ws_url.clone().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(toml: &str) -> Config {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn removing_a_route_target_falls_back_to_normal_selection() {
+        let config = config(
+            r#"
+            port = 28899
+            [method_routes]
+            getSlot = "b"
+            getBalance = "a"
+            [[backends]]
+            label = "a"
+            url = "http://a.invalid"
+            weight = 1
+            [[backends]]
+            label = "b"
+            url = "http://b.invalid"
+            weight = 1
+            "#,
+        );
+        let routing = Routing::new(&config);
+        assert_eq!(routing.method_route("getSlot"), Some("b"));
+
+        let routing = routing.without("b");
+        assert_eq!(routing.method_route("getSlot"), None);
+        assert!(!routing.method_routes.contains_key("getSlot"));
+        assert_eq!(routing.method_route("getBalance"), Some("a"));
+    }
+
+    fn backends(weights: &[u32]) -> Vec<Backend> {
+        let backends: String = weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| {
+                format!(
+                    "[[backends]]\nlabel = \"b{i}\"\nurl = \"http://b{i}.invalid\"\nweight = {weight}\n"
+                )
+            })
+            .collect();
+        config(&format!("port = 28899\n{}", backends)).backends
+    }
+
+    fn batch_routing() -> Routing {
+        Routing::new(&config(
+            r#"
+            port = 28899
+            [method_routes]
+            sendTransaction = "a"
+            simulateTransaction = "a"
+            getProgramAccounts = "b"
+            [[backends]]
+            label = "a"
+            url = "http://a.invalid"
+            weight = 1
+            [[backends]]
+            label = "b"
+            url = "http://b.invalid"
+            weight = 1
+            "#,
+        ))
+    }
+
+    fn methods(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn mixed_batch_routed_to_one_backend_follows_the_route() {
+        let routing = batch_routing();
+        let batch = methods(&["getSlot", "sendTransaction", "simulateTransaction"]);
+        assert_eq!(
+            routing
+                .batch_route_method(&batch)
+                .as_deref()
+                .and_then(|m| routing.method_route(m)),
+            Some("a")
+        );
+    }
+
+    #[test]
+    fn mixed_batch_routed_to_different_backends_uses_normal_selection() {
+        let routing = batch_routing();
+        let batch = methods(&["sendTransaction", "getBalance", "getProgramAccounts"]);
+        assert_eq!(routing.batch_route_method(&batch), None);
+    }
+
+    #[test]
+    fn batch_without_routed_methods_uses_normal_selection() {
+        let routing = batch_routing();
+        assert_eq!(
+            routing.batch_route_method(&methods(&["getSlot", "getBalance"])),
+            None
+        );
+    }
+
+    /// Weighted selection over 50 backends: the cached index against the
+    /// linear scan it replaced. Run with
+    /// `cargo test --release weighted_pick_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn weighted_pick_benchmark() {
+        const PICKS: u32 = 1_000_000;
+        let weights: Vec<u32> = (1..=50).collect();
+        let backends = backends(&weights);
+        let labels: Vec<String> = backends.iter().map(|b| b.label.clone()).collect();
+        let health_state = HealthState::new(labels);
+        let routing = Arc::new(Routing::new(&Config {
+            backends: backends.clone(),
+            ..config("port = 28899\nbackends = []")
+        }));
+        let index = WeightedIndex::new(routing, &health_state);
+        let candidates: Vec<&Backend> = backends.iter().collect();
+
+        let start = Instant::now();
+        for _ in 0..PICKS {
+            std::hint::black_box(index.pick());
+        }
+        let indexed = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..PICKS {
+            std::hint::black_box(pick_by_weight(&candidates, |b| b.weight as f64));
+        }
+        let scanned = start.elapsed();
+
+        println!(
+            "50 backends, {PICKS} picks: index {:?}/pick, scan {:?}/pick",
+            indexed / PICKS,
+            scanned / PICKS
+        );
+        assert!(indexed < scanned);
+    }
+
+    #[test]
+    fn round_robin_spreads_exactly_by_weight() {
+        let backends = backends(&[1, 2, 3]);
+        let candidates: Vec<&Backend> = backends.iter().collect();
+        let round_robin = RoundRobin::new(&backends);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..600 {
+            let backend = round_robin.next(&candidates).unwrap();
+            *counts.entry(backend.label.clone()).or_default() += 1;
+        }
+        assert_eq!(counts["b0"], 100);
+        assert_eq!(counts["b1"], 200);
+        assert_eq!(counts["b2"], 300);
+    }
+
+    #[test]
+    fn round_robin_with_large_weights_splits_by_their_ratio() {
+        let large = backends(&[1_000_000, 2_000_000, 3_000_000]);
+        let candidates: Vec<&Backend> = large.iter().collect();
+        let round_robin = RoundRobin::new(&large);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..6 {
+            let backend = round_robin.next(&candidates).unwrap();
+            *counts.entry(backend.label.clone()).or_default() += 1;
+        }
+        assert_eq!((counts["b0"], counts["b1"], counts["b2"]), (1, 2, 3));
+
+        let lopsided = backends(&[1, u32::MAX]);
+        let candidates: Vec<&Backend> = lopsided.iter().collect();
+        let round_robin = RoundRobin::new(&lopsided);
+        for _ in 0..1000 {
+            assert_eq!(round_robin.next(&candidates).unwrap().label, "b1");
+        }
+    }
+
+    #[test]
+    fn round_robin_skips_unavailable_backends_without_losing_its_place() {
+        let backends = backends(&[1, 2, 3]);
+        let round_robin = RoundRobin::new(&backends);
+        let without_b1: Vec<&Backend> = backends.iter().filter(|b| b.label != "b1").collect();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..600 {
+            let backend = round_robin.next(&without_b1).unwrap();
+            *counts.entry(backend.label.clone()).or_default() += 1;
+        }
+        assert_eq!(counts.get("b1"), None);
+        assert_eq!(counts["b0"] + counts["b2"], 600);
+
+        // All back: the schedule resumes where the cursor is, at exact weights
+        let all: Vec<&Backend> = backends.iter().collect();
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..600 {
+            let backend = round_robin.next(&all).unwrap();
+            *counts.entry(backend.label.clone()).or_default() += 1;
+        }
+        assert_eq!((counts["b0"], counts["b1"], counts["b2"]), (100, 200, 300));
+    }
+
+    #[test]
+    fn power_of_two_choices_prefers_the_less_busy_backend() {
+        let backends = backends(&[1, 1, 1]);
+        let candidates: Vec<&Backend> = backends.iter().collect();
+        let in_flight = InFlight::new(&["b0".to_string(), "b1".to_string(), "b2".to_string()]);
+        let _busy: Vec<InFlightGuard> = (0..10)
+            .filter_map(|_| in_flight.track("b0"))
+            .chain((0..5).filter_map(|_| in_flight.track("b2")))
+            .collect();
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..3000 {
+            let backend = pick_two_choices(
+                &candidates,
+                |b| b.weight as f64,
+                |label| in_flight.count(label),
             )
-        })
+            .unwrap();
+            *counts.entry(backend.label.clone()).or_default() += 1;
+        }
+        // The busiest backend loses every pairing it is drawn into
+        assert_eq!(counts.get("b0"), None);
+        assert!(counts["b1"] > counts["b2"], "{:?}", counts);
+    }
+
+    #[test]
+    fn hash_ring_divides_weights_by_their_gcd() {
+        let scaled = HashRing::new(&backends(&[1000, 3000]));
+        let reduced = HashRing::new(&backends(&[1, 3]));
+        assert_eq!(scaled.points, reduced.points);
+        assert_eq!(scaled.points.len(), 4 * RING_POINTS_PER_WEIGHT as usize);
+    }
+
+    #[test]
+    fn hash_ring_caps_points_for_extreme_weights() {
+        let ring = HashRing::new(&backends(&[1, u32::MAX]));
+        assert!(ring.points.len() as u64 <= MAX_RING_POINTS + 1);
+        assert!(ring.points.iter().any(|&(_, index)| index == 0));
     }
 }